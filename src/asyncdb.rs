@@ -0,0 +1,412 @@
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+use crate::batch::Batch;
+use crate::database::{DataCategory, Database, Result};
+use crate::error::DatabaseError;
+use futures::channel::{mpsc, oneshot};
+use futures::executor::block_on;
+use futures::future::{self, BoxFuture, FutureExt};
+use futures::stream::{self, BoxStream, StreamExt};
+use futures::SinkExt;
+
+// Number of items an iterator stream reads ahead.
+const STREAM_BUFFER: usize = 64;
+/// The default number of threads of the iterator streams.
+pub const ITERATOR_THREADS: usize = 4;
+
+/// An async interface of the database, for the async runtimes like tokio.
+///
+/// Once a write is started, it is applied entirely even if its future is
+/// dropped, so cancelling never leaves a batch partly written.
+pub trait AsyncDatabase: Send + Sync {
+    fn get(
+        &self,
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+    ) -> BoxFuture<'static, Result<Option<Vec<u8>>>>;
+
+    fn get_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+    ) -> BoxFuture<'static, Result<Vec<Option<Vec<u8>>>>>;
+
+    fn insert_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> BoxFuture<'static, Result<()>>;
+
+    /// Apply the operations of the batch atomically.
+    fn write(&self, batch: Batch) -> BoxFuture<'static, Result<()>>;
+
+    /// Stream the keys and values of the category.
+    fn iterator(
+        &self,
+        category: Option<DataCategory>,
+    ) -> BoxStream<'static, Result<(Vec<u8>, Vec<u8>)>>;
+
+    fn flush(&self) -> BoxFuture<'static, Result<()>>;
+}
+
+/// Run a `Database` on a dedicated thread pool, to implement `AsyncDatabase`.
+///
+/// The jobs wait in a bounded queue when all the threads are busy. An iterator
+/// stream holds a thread until it ends or is dropped, so the streams run on a
+/// pool of their own, and wait in its queue when all its threads are streaming.
+pub struct AsyncDB<D: Database + 'static> {
+    db: Arc<D>,
+    pool: Arc<ThreadPool>,
+    // The pool of the iterator streams.
+    iterator_pool: Arc<ThreadPool>,
+    queue_size: usize,
+}
+
+type Job = Box<dyn FnOnce() + Send>;
+
+// The workers stop after the jobs queued once the pool is dropped, without
+// being waited for, so dropping it never blocks.
+struct ThreadPool {
+    sender: futures::lock::Mutex<mpsc::Sender<Job>>,
+}
+
+impl<D: Database + 'static> AsyncDB<D> {
+    /// Run the database on the number of threads, with the size of the queue
+    /// of the waiting jobs.
+    pub fn new(db: D, threads: usize, queue_size: usize) -> Self {
+        AsyncDB {
+            db: Arc::new(db),
+            pool: Arc::new(ThreadPool::new(threads.max(1), queue_size)),
+            iterator_pool: Arc::new(ThreadPool::new(ITERATOR_THREADS, queue_size)),
+            queue_size,
+        }
+    }
+
+    /// Run the iterator streams on the number of threads, `ITERATOR_THREADS`
+    /// by default.
+    pub fn with_iterator_threads(mut self, threads: usize) -> Self {
+        self.iterator_pool = Arc::new(ThreadPool::new(threads.max(1), self.queue_size));
+        self
+    }
+
+    /// The database, to call the blocking methods.
+    pub fn database(&self) -> &D {
+        &self.db
+    }
+
+    // Run the function with the database on the thread pool.
+    fn spawn<T, F>(&self, f: F) -> BoxFuture<'static, Result<T>>
+    where
+        T: Send + 'static,
+        F: FnOnce(&D) -> Result<T> + Send + 'static,
+    {
+        let db = Arc::clone(&self.db);
+        let pool = Arc::clone(&self.pool);
+        async move {
+            let (sender, receiver) = oneshot::channel();
+            let job: Job = Box::new(move || {
+                let _ = sender.send(f(&db));
+            });
+            pool.submit(job).await?;
+            // Canceled if the job panics.
+            receiver.await.map_err(|_| map_pool_err())?
+        }
+        .boxed()
+    }
+}
+
+impl<D: Database + 'static> AsyncDatabase for AsyncDB<D> {
+    fn get(
+        &self,
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+    ) -> BoxFuture<'static, Result<Option<Vec<u8>>>> {
+        self.spawn(move |db| db.get(category, &key))
+    }
+
+    fn get_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+    ) -> BoxFuture<'static, Result<Vec<Option<Vec<u8>>>>> {
+        self.spawn(move |db| db.get_batch(category, &keys))
+    }
+
+    fn insert_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> BoxFuture<'static, Result<()>> {
+        self.spawn(move |db| db.insert_batch(category, keys, values))
+    }
+
+    fn write(&self, batch: Batch) -> BoxFuture<'static, Result<()>> {
+        self.spawn(move |db| db.write(batch))
+    }
+
+    fn iterator(
+        &self,
+        category: Option<DataCategory>,
+    ) -> BoxStream<'static, Result<(Vec<u8>, Vec<u8>)>> {
+        let (mut sender, receiver) = mpsc::channel(STREAM_BUFFER);
+        let db = Arc::clone(&self.db);
+        let job: Job = Box::new(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let iter = db.iterator(category).ok_or(DatabaseError::NotFound)?;
+                for item in iter {
+                    let item = item.map(|(key, value)| (key.into_vec(), value.into_vec()));
+                    if block_on(sender.send(item)).is_err() {
+                        // The stream is dropped.
+                        break;
+                    }
+                }
+                Ok(())
+            }));
+            let err = match result {
+                Ok(Ok(())) => return,
+                Ok(Err(err)) => err,
+                Err(_) => DatabaseError::Internal("iterator panicked".to_string()),
+            };
+            let _ = block_on(sender.send(Err(err)));
+        });
+
+        let pool = Arc::clone(&self.iterator_pool);
+        async move {
+            match pool.submit(job).await {
+                Ok(()) => receiver.boxed(),
+                Err(err) => stream::once(future::ready(Err(err))).boxed(),
+            }
+        }
+        .flatten_stream()
+        .boxed()
+    }
+
+    fn flush(&self) -> BoxFuture<'static, Result<()>> {
+        self.spawn(|db| db.flush())
+    }
+}
+
+impl ThreadPool {
+    fn new(threads: usize, queue_size: usize) -> Self {
+        let (sender, receiver) = mpsc::channel::<Job>(queue_size);
+        let receiver = Arc::new(Mutex::new(receiver));
+        for _ in 0..threads {
+            let receiver = Arc::clone(&receiver);
+            thread::spawn(move || loop {
+                let job = match receiver.lock() {
+                    Ok(mut receiver) => block_on(receiver.next()),
+                    Err(_) => None,
+                };
+                match job {
+                    // A panic only fails the job.
+                    Some(job) => {
+                        let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                    }
+                    None => break,
+                }
+            });
+        }
+
+        ThreadPool {
+            sender: futures::lock::Mutex::new(sender),
+        }
+    }
+
+    // Queue the job, waiting while the queue is full.
+    async fn submit(&self, job: Job) -> Result<()> {
+        self.sender
+            .lock()
+            .await
+            .send(job)
+            .await
+            .map_err(|_| map_pool_err())
+    }
+}
+
+fn map_pool_err() -> DatabaseError {
+    DatabaseError::Internal("thread pool error".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{AsyncDB, AsyncDatabase};
+    use crate::batch::Batch;
+    use crate::database::{DBIterator, DataCategory, Database, Result};
+    use crate::error::DatabaseError;
+    use crate::memorydb::MemoryDB;
+    use crate::test::{compare_and_swap, write_batch};
+    use futures::executor::block_on;
+    use futures::StreamExt;
+
+    #[test]
+    fn test_get_insert() {
+        let db = AsyncDB::new(MemoryDB::open(), 2, 4);
+        let category = Some(DataCategory::State);
+        let keys = vec![b"test1".to_vec(), b"test2".to_vec()];
+
+        block_on(async {
+            db.insert_batch(category.clone(), keys.clone(), keys.clone())
+                .await
+                .unwrap();
+            let mut batch = Batch::new();
+            batch.remove(category.clone(), keys[0].clone());
+            db.write(batch).await.unwrap();
+
+            assert_eq!(
+                db.get(category.clone(), keys[1].clone()).await,
+                Ok(Some(keys[1].clone()))
+            );
+            assert_eq!(
+                db.get_batch(category, keys.clone()).await,
+                Ok(vec![None, Some(keys[1].clone())])
+            );
+            assert_eq!(
+                db.insert_batch(None, keys, vec![]).await,
+                Err(DatabaseError::InvalidData)
+            );
+        });
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn test_iterator() {
+        use crate::config::Config;
+        use crate::rocksdb::RocksDB;
+
+        let mut cfg = Config::with_category_num(Some(1));
+        cfg.wal = false;
+        let db = RocksDB::open("rocksdb_test/async_iterator", &cfg).unwrap();
+        let db = AsyncDB::new(db, 1, 1);
+        let category = Some(DataCategory::State);
+        let keys: Vec<_> = (0..100u8).map(|i| vec![i]).collect();
+
+        block_on(async {
+            db.insert_batch(category.clone(), keys.clone(), keys.clone())
+                .await
+                .unwrap();
+            let items: Vec<_> = db.iterator(category.clone()).collect().await;
+            let items: Vec<_> = items.into_iter().map(|item| item.unwrap().0).collect();
+            assert_eq!(items, keys);
+
+            // The open streams do not hold the only thread of the pool.
+            let mut first = db.iterator(category.clone());
+            let mut second = db.iterator(category.clone());
+            assert_eq!(first.next().await, Some(Ok((vec![0], vec![0]))));
+            assert_eq!(second.next().await, Some(Ok((vec![0], vec![0]))));
+            assert_eq!(db.get(category.clone(), vec![1]).await, Ok(Some(vec![1])));
+        });
+
+        // A stream waits for a thread of the iterators while the others stream.
+        let db = db.with_iterator_threads(1);
+        block_on(async {
+            let mut first = db.iterator(category.clone());
+            let second = db.iterator(category.clone());
+            assert_eq!(first.next().await, Some(Ok((vec![0], vec![0]))));
+            drop(first);
+            let items: Vec<_> = second.collect().await;
+            assert_eq!(items.len(), keys.len());
+        });
+
+        let _ = std::fs::remove_dir_all("rocksdb_test/async_iterator");
+    }
+
+    // Panic in the iterator, the other methods are of `MemoryDB` or the defaults.
+    struct PanicDB(MemoryDB);
+
+    impl Database for PanicDB {
+        fn get(&self, category: Option<DataCategory>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+            self.0.get(category, key)
+        }
+
+        fn get_batch(
+            &self,
+            category: Option<DataCategory>,
+            keys: &[Vec<u8>],
+        ) -> Result<Vec<Option<Vec<u8>>>> {
+            self.0.get_batch(category, keys)
+        }
+
+        fn insert(
+            &self,
+            category: Option<DataCategory>,
+            key: Vec<u8>,
+            value: Vec<u8>,
+        ) -> Result<()> {
+            self.0.insert(category, key, value)
+        }
+
+        fn insert_batch(
+            &self,
+            category: Option<DataCategory>,
+            keys: Vec<Vec<u8>>,
+            values: Vec<Vec<u8>>,
+        ) -> Result<()> {
+            self.0.insert_batch(category, keys, values)
+        }
+
+        fn contains(&self, category: Option<DataCategory>, key: &[u8]) -> Result<bool> {
+            self.0.contains(category, key)
+        }
+
+        fn remove(&self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+            self.0.remove(category, key)
+        }
+
+        fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()> {
+            self.0.remove_batch(category, keys)
+        }
+
+        fn compare_and_swap(
+            &self,
+            category: Option<DataCategory>,
+            key: &[u8],
+            expected: Option<&[u8]>,
+            new: Option<Vec<u8>>,
+        ) -> Result<bool> {
+            self.0.compare_and_swap(category, key, expected, new)
+        }
+
+        fn restore(&mut self, new_db: &str) -> Result<()> {
+            self.0.restore(new_db)
+        }
+
+        fn iterator(&self, _category: Option<DataCategory>) -> Option<DBIterator<'_>> {
+            panic!("iterator");
+        }
+
+        fn close(&mut self) {
+            self.0.close()
+        }
+
+        fn flush(&self) -> Result<()> {
+            self.0.flush()
+        }
+    }
+
+    #[test]
+    fn test_default_methods() {
+        let db = PanicDB(MemoryDB::open());
+
+        write_batch(&db);
+        compare_and_swap(&db, None);
+        assert_eq!(
+            db.merge(Some(DataCategory::State), b"test".to_vec(), vec![0; 8]),
+            Err(DatabaseError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_iterator_panic() {
+        // The panic fails the stream only.
+        let db = AsyncDB::new(PanicDB(MemoryDB::open()), 1, 1);
+        block_on(async {
+            let items: Vec<_> = db.iterator(None).collect().await;
+            assert!(matches!(items[..], [Err(DatabaseError::Internal(_))]));
+            assert_eq!(db.get(None, vec![1]).await, Ok(None));
+        });
+    }
+}
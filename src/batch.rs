@@ -0,0 +1,275 @@
+use std::collections::HashMap;
+
+use crate::database::{DataCategory, Database, Result};
+use crate::error::DatabaseError;
+
+/// A write operation in a batch.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BatchOp {
+    Insert {
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    },
+    Remove {
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+    },
+    /// Merge the operand into the value, see `Database::merge`.
+    Merge {
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    },
+}
+
+/// Write operations across data categories, applied atomically by `Database::write`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Batch {
+    ops: Vec<BatchOp>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) {
+        self.ops.push(BatchOp::Insert {
+            category,
+            key,
+            value,
+        });
+    }
+
+    pub fn remove(&mut self, category: Option<DataCategory>, key: Vec<u8>) {
+        self.ops.push(BatchOp::Remove { category, key });
+    }
+
+    pub fn merge(&mut self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) {
+        self.ops.push(BatchOp::Merge {
+            category,
+            key,
+            operand,
+        });
+    }
+
+    pub fn ops(&self) -> &[BatchOp] {
+        &self.ops
+    }
+
+    pub fn len(&self) -> usize {
+        self.ops.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+}
+
+impl IntoIterator for Batch {
+    type Item = BatchOp;
+    type IntoIter = std::vec::IntoIter<BatchOp>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.ops.into_iter()
+    }
+}
+
+// The key of a write in the index.
+type IndexKey = (Option<DataCategory>, Vec<u8>);
+
+/// A batch indexed by key, so reads through it see its pending writes.
+///
+/// Savepoints can be nested, and rolling back to one undoes the writes since,
+/// without rebuilding the batch.
+#[derive(Debug, Clone, Default)]
+pub struct IndexedBatch {
+    batch: Batch,
+    // The latest write of each key, `None` for a removed key.
+    index: HashMap<IndexKey, Option<Vec<u8>>>,
+    // The index entry replaced by each operation, for the rollback.
+    undo: Vec<Option<Option<Vec<u8>>>>,
+    // The number of operations at each savepoint.
+    savepoints: Vec<usize>,
+}
+
+impl IndexedBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn insert(&mut self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) {
+        let replaced = self
+            .index
+            .insert((category.clone(), key.clone()), Some(value.clone()));
+        self.undo.push(replaced);
+        self.batch.insert(category, key, value);
+    }
+
+    pub fn remove(&mut self, category: Option<DataCategory>, key: Vec<u8>) {
+        let replaced = self.index.insert((category.clone(), key.clone()), None);
+        self.undo.push(replaced);
+        self.batch.remove(category, key);
+    }
+
+    /// Get the value from the pending writes, or from the database if the key
+    /// is not written in the batch.
+    ///
+    /// The pending writes are read as plain values, also in a ref-counted
+    /// category: a pending remove reads as absent, though the key may still be
+    /// referenced once the batch is written.
+    pub fn get<D: Database>(
+        &self,
+        db: &D,
+        category: Option<DataCategory>,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        match self.index.get(&(category.clone(), key.to_vec())) {
+            Some(value) => Ok(value.clone()),
+            None => db.get(category, key),
+        }
+    }
+
+    /// Whether the key exists, read as `get` does.
+    pub fn contains<D: Database>(
+        &self,
+        db: &D,
+        category: Option<DataCategory>,
+        key: &[u8],
+    ) -> Result<bool> {
+        match self.index.get(&(category.clone(), key.to_vec())) {
+            Some(value) => Ok(value.is_some()),
+            None => db.contains(category, key),
+        }
+    }
+
+    /// Mark the current position of the batch.
+    pub fn set_savepoint(&mut self) {
+        self.savepoints.push(self.batch.len());
+    }
+
+    /// Undo the writes since the latest savepoint, and remove it.
+    ///
+    /// Return `DatabaseError::NotFound` if there is no savepoint.
+    pub fn rollback_to_savepoint(&mut self) -> Result<()> {
+        let len = self.savepoints.pop().ok_or(DatabaseError::NotFound)?;
+        while self.batch.len() > len {
+            let op = self
+                .batch
+                .ops
+                .pop()
+                .expect("ops are longer than the savepoint");
+            let replaced = self.undo.pop().expect("undo is as long as ops");
+            let key = match op {
+                BatchOp::Insert { category, key, .. }
+                | BatchOp::Remove { category, key }
+                | BatchOp::Merge { category, key, .. } => (category, key),
+            };
+            match replaced {
+                Some(value) => self.index.insert(key, value),
+                None => self.index.remove(&key),
+            };
+        }
+        Ok(())
+    }
+
+    /// Remove the latest savepoint, keeping the writes since.
+    ///
+    /// Return `DatabaseError::NotFound` if there is no savepoint.
+    pub fn pop_savepoint(&mut self) -> Result<()> {
+        self.savepoints
+            .pop()
+            .map(|_| ())
+            .ok_or(DatabaseError::NotFound)
+    }
+
+    pub fn batch(&self) -> &Batch {
+        &self.batch
+    }
+
+    /// Take the batch to write it with `Database::write`.
+    pub fn into_batch(self) -> Batch {
+        self.batch
+    }
+
+    pub fn len(&self) -> usize {
+        self.batch.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.batch.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndexedBatch;
+    use crate::database::{DataCategory, Database};
+    use crate::error::DatabaseError;
+    use crate::memorydb::MemoryDB;
+
+    #[test]
+    fn test_read_through() {
+        let db = MemoryDB::open();
+        let category = Some(DataCategory::State);
+        let data1 = b"test1".to_vec();
+        let data2 = b"test2".to_vec();
+        db.insert(category.clone(), data1.clone(), data1.clone())
+            .unwrap();
+
+        let mut batch = IndexedBatch::new();
+        batch.insert(category.clone(), data2.clone(), data2.clone());
+        assert_eq!(
+            batch.get(&db, category.clone(), &data1),
+            Ok(Some(data1.clone()))
+        );
+        assert_eq!(
+            batch.get(&db, category.clone(), &data2),
+            Ok(Some(data2.clone()))
+        );
+        // Only in the category written
+        assert_eq!(batch.get(&db, None, &data2), Ok(None));
+
+        batch.remove(category.clone(), data1.clone());
+        assert_eq!(batch.contains(&db, category.clone(), &data1), Ok(false));
+        assert_eq!(db.contains(category.clone(), &data1), Ok(true));
+
+        db.write(batch.into_batch()).unwrap();
+        assert_eq!(db.contains(category.clone(), &data1), Ok(false));
+        assert_eq!(db.get(category, &data2), Ok(Some(data2)));
+    }
+
+    #[test]
+    fn test_savepoint() {
+        let db = MemoryDB::open();
+        let data1 = b"test1".to_vec();
+        let data2 = b"test2".to_vec();
+
+        let mut batch = IndexedBatch::new();
+        batch.insert(None, data1.clone(), data1.clone());
+        batch.set_savepoint();
+        batch.insert(None, data1.clone(), data2.clone());
+        batch.set_savepoint();
+        batch.remove(None, data1.clone());
+        batch.insert(None, data2.clone(), data2.clone());
+        assert_eq!(batch.get(&db, None, &data1), Ok(None));
+
+        batch.rollback_to_savepoint().unwrap();
+        assert_eq!(batch.get(&db, None, &data1), Ok(Some(data2.clone())));
+        assert_eq!(batch.contains(&db, None, &data2), Ok(false));
+        assert_eq!(batch.len(), 2);
+
+        batch.rollback_to_savepoint().unwrap();
+        assert_eq!(batch.get(&db, None, &data1), Ok(Some(data1.clone())));
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch.rollback_to_savepoint(), Err(DatabaseError::NotFound));
+
+        // Popped savepoints keep the writes.
+        batch.set_savepoint();
+        batch.insert(None, data2.clone(), data2.clone());
+        batch.pop_savepoint().unwrap();
+        assert_eq!(batch.pop_savepoint(), Err(DatabaseError::NotFound));
+        assert_eq!(batch.get(&db, None, &data2), Ok(Some(data2)));
+    }
+}
@@ -0,0 +1,642 @@
+use std::ops::Deref;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+use crate::batch::{Batch, BatchOp};
+use crate::database::{DBIterator, DataCategory, Database, Result};
+use crate::error::{map_mutex_err, DatabaseError};
+
+/// The default key of the sequence number of the last event, in the data
+/// without category.
+pub const SEQUENCE_KEY: &[u8] = b"changefeed_sequence";
+
+/// A committed write of a key, `value` is `None` for a remove.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    pub seq: u64,
+    pub category: Option<DataCategory>,
+    pub key: Vec<u8>,
+    pub value: Option<Vec<u8>>,
+}
+
+// The sending end of a subscription.
+enum Subscriber {
+    Unbounded(Sender<Event>),
+    Bounded(SyncSender<Event>),
+}
+
+impl Subscriber {
+    // False if the receiver is gone, or its buffer is full.
+    fn send(&self, event: &Event) -> bool {
+        match self {
+            Subscriber::Unbounded(sender) => sender.send(event.clone()).is_ok(),
+            Subscriber::Bounded(sender) => sender.try_send(event.clone()).is_ok(),
+        }
+    }
+}
+
+// Receive the events of the keys with the prefix in the category.
+struct Watcher {
+    id: u64,
+    category: Option<DataCategory>,
+    prefix: Vec<u8>,
+    sender: Sender<Event>,
+}
+
+impl Watcher {
+    fn matches(&self, event: &Event) -> bool {
+        self.category == event.category && event.key.starts_with(&self.prefix)
+    }
+}
+
+#[derive(Default)]
+struct Feed {
+    // The sequence number of the last event
+    seq: u64,
+    subscribers: Vec<Subscriber>,
+    watchers: Vec<Watcher>,
+    // The id of the next watcher
+    next_watcher_id: u64,
+}
+
+impl Feed {
+    // Whether the events are received by anyone, so that they are built.
+    fn is_observed(&self) -> bool {
+        !self.subscribers.is_empty() || !self.watchers.is_empty()
+    }
+
+    fn publish(&mut self, category: Option<DataCategory>, key: Vec<u8>, value: Option<Vec<u8>>) {
+        self.seq += 1;
+        let event = Event {
+            seq: self.seq,
+            category,
+            key,
+            value,
+        };
+        // Drop the subscribers whose receivers are gone or full.
+        self.subscribers
+            .retain(|subscriber| subscriber.send(&event));
+        self.watchers.retain(|watcher| {
+            !watcher.matches(&event) || watcher.sender.send(event.clone()).is_ok()
+        });
+    }
+}
+
+/// Publish the committed writes to any database to the subscribers, in the
+/// order they are committed.
+///
+/// The writes through it are serialized, and each written key gets the next
+/// sequence number, from 1. Writes not through it, and `restore`, are not
+/// published.
+///
+/// The sequence number is stored in the same batch as the writes, and continues
+/// after reopening. So a consumer which keeps the last number it has seen can
+/// tell whether it missed any write. For `compare_and_swap` it is stored before
+/// the write, and put back if nothing is swapped, so it may skip a number after
+/// a crash or an error.
+pub struct ObservableDB<D: Database> {
+    db: D,
+    // Where the sequence number is stored.
+    sequence_category: Option<DataCategory>,
+    sequence_key: Vec<u8>,
+    // Held across each write, so that the events are in the order of the writes.
+    feed: Arc<Mutex<Feed>>,
+}
+
+/// The events of `ObservableDB::watch`, received through the `Receiver` it
+/// derefs to. Dropping it stops the watch.
+pub struct Watch {
+    receiver: Receiver<Event>,
+    id: u64,
+    feed: Weak<Mutex<Feed>>,
+}
+
+impl Deref for Watch {
+    type Target = Receiver<Event>;
+
+    fn deref(&self) -> &Receiver<Event> {
+        &self.receiver
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        if let Some(feed) = self.feed.upgrade() {
+            if let Ok(mut feed) = feed.lock() {
+                feed.watchers.retain(|watcher| watcher.id != self.id);
+            }
+        }
+    }
+}
+
+impl<D: Database> ObservableDB<D> {
+    /// Observe the database, continuing from the sequence number stored at
+    /// `SEQUENCE_KEY` in the data without category.
+    pub fn new(db: D) -> Result<Self> {
+        Self::with_sequence_key(db, None, SEQUENCE_KEY.to_vec())
+    }
+
+    /// Observe the database, continuing from the sequence number stored at the
+    /// key in the category, which the writes should not touch. The category must
+    /// not be ref-counted or have a merge operator.
+    ///
+    /// The number is read and written back, so that an error of the category,
+    /// e.g. it is not in the database, is returned here rather than by the writes.
+    pub fn with_sequence_key(db: D, category: Option<DataCategory>, key: Vec<u8>) -> Result<Self> {
+        let db = ObservableDB {
+            db,
+            sequence_category: category,
+            sequence_key: key,
+            feed: Arc::new(Mutex::new(Feed::default())),
+        };
+        let seq = db.load_sequence()?;
+        db.store_sequence(seq)?;
+        db.lock()?.seq = seq;
+        Ok(db)
+    }
+
+    /// The observed database.
+    pub fn database(&self) -> &D {
+        &self.db
+    }
+
+    pub fn into_inner(self) -> D {
+        self.db
+    }
+
+    /// Receive the events after the subscription. The events are buffered until
+    /// received, and the subscription ends when the receiver is dropped.
+    pub fn subscribe(&self) -> Result<Receiver<Event>> {
+        let (sender, receiver) = mpsc::channel();
+        self.lock()?.subscribers.push(Subscriber::Unbounded(sender));
+        Ok(receiver)
+    }
+
+    /// Receive the events after the subscription, buffering at most `capacity`
+    /// of them. The writes never wait for the subscriber: once its buffer is
+    /// full, it is dropped and the receiver is disconnected after the buffered
+    /// events. Then subscribe again and read the missed writes from the database.
+    pub fn subscribe_bounded(&self, capacity: usize) -> Result<Receiver<Event>> {
+        let (sender, receiver) = mpsc::sync_channel(capacity);
+        self.lock()?.subscribers.push(Subscriber::Bounded(sender));
+        Ok(receiver)
+    }
+
+    /// Receive the events of the keys with the prefix in the category, the whole
+    /// key to watch a single key, until the `Watch` is dropped. Watch before
+    /// checking whether the key exists, so that no write is missed in between.
+    pub fn watch(&self, category: Option<DataCategory>, prefix: &[u8]) -> Result<Watch> {
+        let (sender, receiver) = mpsc::channel();
+        let mut feed = self.lock()?;
+        let id = feed.next_watcher_id;
+        feed.next_watcher_id += 1;
+        feed.watchers.push(Watcher {
+            id,
+            category,
+            prefix: prefix.to_vec(),
+            sender,
+        });
+        Ok(Watch {
+            receiver,
+            id,
+            feed: Arc::downgrade(&self.feed),
+        })
+    }
+
+    /// The sequence number of the last event, 0 before any write.
+    pub fn sequence(&self) -> Result<u64> {
+        Ok(self.lock()?.seq)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Feed>> {
+        self.feed.lock().map_err(|_| map_mutex_err())
+    }
+
+    // Write the batch with the sequence number after it, and publish it.
+    fn commit(&self, feed: &mut Feed, mut batch: Batch) -> Result<()> {
+        let seq = feed.seq + batch.len() as u64;
+        let ops = if feed.is_observed() {
+            Some(batch.clone())
+        } else {
+            None
+        };
+        batch.insert(
+            self.sequence_category.clone(),
+            self.sequence_key.clone(),
+            seq.to_le_bytes().to_vec(),
+        );
+        self.db.write(batch)?;
+
+        match ops {
+            Some(ops) => {
+                for op in ops {
+                    match op {
+                        BatchOp::Insert {
+                            category,
+                            key,
+                            value,
+                        } => feed.publish(category, key, Some(value)),
+                        BatchOp::Remove { category, key } => feed.publish(category, key, None),
+                        // The merged value, after the whole batch.
+                        BatchOp::Merge { category, key, .. } => {
+                            let value = self.db.get(category.clone(), &key)?;
+                            feed.publish(category, key, value)
+                        }
+                    }
+                }
+            }
+            None => feed.seq = seq,
+        }
+        Ok(())
+    }
+
+    // Store the sequence number, for the writes which can not be in a batch.
+    fn store_sequence(&self, seq: u64) -> Result<()> {
+        self.db.insert(
+            self.sequence_category.clone(),
+            self.sequence_key.clone(),
+            seq.to_le_bytes().to_vec(),
+        )
+    }
+
+    fn load_sequence(&self) -> Result<u64> {
+        match self
+            .db
+            .get(self.sequence_category.clone(), &self.sequence_key)?
+        {
+            Some(value) => {
+                let bytes = <[u8; 8]>::try_from(value.as_slice())
+                    .map_err(|_| DatabaseError::InvalidData)?;
+                Ok(u64::from_le_bytes(bytes))
+            }
+            None => Ok(0),
+        }
+    }
+}
+
+impl<D: Database> Database for ObservableDB<D> {
+    fn get(&self, category: Option<DataCategory>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.db.get(category, key)
+    }
+
+    fn get_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: &[Vec<u8>],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        self.db.get_batch(category, keys)
+    }
+
+    fn insert(&self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.insert(category, key, value);
+        self.commit(&mut *self.lock()?, batch)
+    }
+
+    fn insert_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        if keys.len() != values.len() {
+            return Err(DatabaseError::InvalidData);
+        }
+
+        let mut batch = Batch::new();
+        for (key, value) in keys.into_iter().zip(values) {
+            batch.insert(category.clone(), key, value);
+        }
+        self.commit(&mut *self.lock()?, batch)
+    }
+
+    fn contains(&self, category: Option<DataCategory>, key: &[u8]) -> Result<bool> {
+        self.db.contains(category, key)
+    }
+
+    fn remove(&self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.remove(category, key.to_vec());
+        self.commit(&mut *self.lock()?, batch)
+    }
+
+    fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()> {
+        let mut batch = Batch::new();
+        for key in keys {
+            batch.remove(category.clone(), key.to_vec());
+        }
+        self.commit(&mut *self.lock()?, batch)
+    }
+
+    /// Publish the merged value.
+    fn merge(&self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.merge(category, key, operand);
+        self.commit(&mut *self.lock()?, batch)
+    }
+
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mut feed = self.lock()?;
+        self.store_sequence(feed.seq + 1)?;
+        let observed_value = if feed.is_observed() {
+            Some(new.clone())
+        } else {
+            None
+        };
+        let swapped = self
+            .db
+            .compare_and_swap(category.clone(), key, expected, new);
+
+        match (&swapped, observed_value) {
+            (Ok(true), Some(value)) => feed.publish(category, key.to_vec(), value),
+            // Not swapped, put back the stored sequence number.
+            (Ok(false), _) => self.store_sequence(feed.seq)?,
+            // Skip the stored number, as it is not known whether it is swapped.
+            _ => feed.seq += 1,
+        }
+        swapped
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        self.commit(&mut *self.lock()?, batch)
+    }
+
+    /// Continue from the sequence number of the restored database.
+    fn restore(&mut self, new_db: &str) -> Result<()> {
+        self.db.restore(new_db)?;
+        let seq = self.load_sequence()?;
+        self.lock()?.seq = seq;
+        Ok(())
+    }
+
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
+        self.db.iterator(category)
+    }
+
+    fn close(&mut self) {
+        self.db.close()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.db.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Event, ObservableDB, SEQUENCE_KEY};
+    use crate::batch::Batch;
+    use crate::database::{DataCategory, Database};
+    use crate::error::DatabaseError;
+    use crate::memorydb::MemoryDB;
+    use crate::test::{
+        batch_op, compare_and_swap, insert_get_contains_remove, merge, merge_config, write_batch,
+    };
+    use std::sync::mpsc::Receiver;
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn test_database() {
+        let db = ObservableDB::new(MemoryDB::with_config(&merge_config()).unwrap()).unwrap();
+
+        insert_get_contains_remove(&db, None);
+        batch_op(&db, Some(DataCategory::State));
+        write_batch(&db);
+        compare_and_swap(&db, Some(DataCategory::Extra));
+        merge(&db);
+    }
+
+    #[test]
+    fn test_subscribe() {
+        let db = ObservableDB::new(MemoryDB::with_config(&merge_config()).unwrap()).unwrap();
+        let data = b"test".to_vec();
+        db.insert(None, data.clone(), data.clone()).unwrap();
+        assert_eq!(db.sequence(), Ok(1));
+
+        let events = db.subscribe().unwrap();
+        let mut batch = Batch::new();
+        batch.insert(Some(DataCategory::Extra), data.clone(), data.clone());
+        batch.remove(None, data.clone());
+        db.write(batch).unwrap();
+        assert_eq!(db.insert_if_absent(None, data.clone(), vec![]), Ok(true));
+        // Not swapped
+        assert_eq!(db.insert_if_absent(None, data.clone(), vec![]), Ok(false));
+        db.merge(
+            Some(DataCategory::State),
+            data.clone(),
+            2u64.to_le_bytes().to_vec(),
+        )
+        .unwrap();
+        // Failed
+        assert!(db.merge(None, data.clone(), vec![]).is_err());
+
+        let event = |seq, category, value| Event {
+            seq,
+            category,
+            key: data.clone(),
+            value,
+        };
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            vec![
+                event(2, Some(DataCategory::Extra), Some(data.clone())),
+                event(3, None, None),
+                event(4, None, Some(vec![])),
+                event(
+                    5,
+                    Some(DataCategory::State),
+                    Some(2u64.to_le_bytes().to_vec())
+                ),
+            ]
+        );
+
+        // Unsubscribe
+        drop(events);
+        db.remove(None, &data).unwrap();
+        assert_eq!(db.feed.lock().unwrap().subscribers.len(), 0);
+    }
+
+    #[test]
+    fn test_concurrent_writes() {
+        let db = Arc::new(ObservableDB::new(MemoryDB::open()).unwrap());
+        let events = db.subscribe().unwrap();
+
+        let handles: Vec<_> = (0..4u8)
+            .map(|i| {
+                let db = Arc::clone(&db);
+                thread::spawn(move || {
+                    for j in 0..100u8 {
+                        db.insert(None, vec![i, j], vec![j]).unwrap();
+                    }
+                })
+            })
+            .collect();
+        for handle in handles {
+            handle.join().unwrap();
+        }
+
+        let seqs: Vec<u64> = events.try_iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, (1..=400).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_watch() {
+        let db = ObservableDB::new(MemoryDB::open()).unwrap();
+        let extra = Some(DataCategory::Extra);
+        let hashes = db.watch(extra.clone(), b"hash").unwrap();
+        let key = db.watch(extra.clone(), b"hash1").unwrap();
+
+        db.insert(extra.clone(), b"hash1".to_vec(), vec![1])
+            .unwrap();
+        db.insert_batch(
+            extra.clone(),
+            vec![b"hash2".to_vec(), b"number".to_vec()],
+            vec![vec![2], vec![3]],
+        )
+        .unwrap();
+        // Another category
+        db.insert(None, b"hash3".to_vec(), vec![]).unwrap();
+        db.remove_batch(extra.clone(), &[b"hash1".to_vec(), b"hash2".to_vec()])
+            .unwrap();
+
+        let keys = |events: &Receiver<Event>| {
+            events
+                .try_iter()
+                .map(|event| (event.seq, event.key, event.value))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            keys(&hashes),
+            vec![
+                (1, b"hash1".to_vec(), Some(vec![1])),
+                (2, b"hash2".to_vec(), Some(vec![2])),
+                (5, b"hash1".to_vec(), None),
+                (6, b"hash2".to_vec(), None),
+            ]
+        );
+        assert_eq!(
+            keys(&key),
+            vec![
+                (1, b"hash1".to_vec(), Some(vec![1])),
+                (5, b"hash1".to_vec(), None),
+            ]
+        );
+
+        // Unwatch without any write.
+        drop(key);
+        assert_eq!(db.feed.lock().unwrap().watchers.len(), 1);
+        // Dropped after the database.
+        drop(db);
+        drop(hashes);
+    }
+
+    #[test]
+    fn test_wait_for_key() {
+        let db = Arc::new(ObservableDB::new(MemoryDB::open()).unwrap());
+        let extra = Some(DataCategory::Extra);
+        let key = b"block_hash".to_vec();
+
+        // Watch before checking the key.
+        let events = db.watch(extra.clone(), &key).unwrap();
+        assert_eq!(db.contains(extra.clone(), &key), Ok(false));
+
+        let writer = {
+            let (db, extra, key) = (Arc::clone(&db), extra.clone(), key.clone());
+            thread::spawn(move || db.insert(extra, key, vec![1]).unwrap())
+        };
+        let event = events.recv_timeout(Duration::from_secs(10)).unwrap();
+        assert_eq!(event.value, Some(vec![1]));
+        writer.join().unwrap();
+        assert_eq!(db.get(extra, &key), Ok(Some(vec![1])));
+    }
+
+    #[test]
+    fn test_subscribe_bounded() {
+        let db = ObservableDB::new(MemoryDB::open()).unwrap();
+        let events = db.subscribe_bounded(2).unwrap();
+        db.insert_batch(None, vec![vec![1], vec![2], vec![3]], vec![vec![]; 3])
+            .unwrap();
+
+        // Dropped when it is full, after the buffered events.
+        assert_eq!(db.feed.lock().unwrap().subscribers.len(), 0);
+        let seqs: Vec<u64> = events.iter().map(|event| event.seq).collect();
+        assert_eq!(seqs, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_stored_sequence() {
+        let db = ObservableDB::new(MemoryDB::with_config(&merge_config()).unwrap()).unwrap();
+        db.insert(None, b"test".to_vec(), vec![]).unwrap();
+        db.merge(
+            Some(DataCategory::State),
+            b"test".to_vec(),
+            1u64.to_le_bytes().to_vec(),
+        )
+        .unwrap();
+        assert_eq!(
+            db.insert_if_absent(None, b"test".to_vec(), vec![]),
+            Ok(false)
+        );
+        assert_eq!(db.sequence(), Ok(2));
+
+        // Continue after reopening.
+        let db = ObservableDB::new(db.into_inner()).unwrap();
+        assert_eq!(db.sequence(), Ok(2));
+        let events = db.subscribe().unwrap();
+        db.remove(None, b"test").unwrap();
+        assert_eq!(events.try_recv().map(|event| event.seq), Ok(3));
+        assert_eq!(
+            db.get(None, SEQUENCE_KEY),
+            Ok(Some(3u64.to_le_bytes().to_vec()))
+        );
+    }
+
+    #[test]
+    fn test_sequence_key() {
+        let db = ObservableDB::with_sequence_key(
+            MemoryDB::open(),
+            Some(DataCategory::Other),
+            b"seq".to_vec(),
+        )
+        .unwrap();
+        db.insert(None, b"test".to_vec(), vec![]).unwrap();
+        assert_eq!(
+            db.get(Some(DataCategory::Other), b"seq"),
+            Ok(Some(1u64.to_le_bytes().to_vec()))
+        );
+        assert_eq!(db.get(None, SEQUENCE_KEY), Ok(None));
+
+        // A malformed sequence number is rejected.
+        let db = db.into_inner();
+        db.insert(Some(DataCategory::Other), b"seq".to_vec(), vec![1])
+            .unwrap();
+        assert!(matches!(
+            ObservableDB::with_sequence_key(db, Some(DataCategory::Other), b"seq".to_vec()),
+            Err(DatabaseError::InvalidData)
+        ));
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn test_sequence_category_error() {
+        use crate::config::Config;
+        use crate::rocksdb::RocksDB;
+
+        // Without the column of the sequence number.
+        let db = RocksDB::open("rocksdb_test/changefeed_category", &Config::default()).unwrap();
+        assert!(matches!(
+            ObservableDB::with_sequence_key(db, Some(DataCategory::Other), b"seq".to_vec()),
+            Err(DatabaseError::NotFound)
+        ));
+
+        let _ = std::fs::remove_dir_all("rocksdb_test/changefeed_category");
+    }
+}
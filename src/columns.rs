@@ -15,7 +15,11 @@ const COL_TRACE: &str = "col4";
 /// TBD. For the empty accounts bloom filter.
 const COL_ACCOUNT_BLOOM: &str = "col5";
 const COL_OTHER: &str = "col6";
-pub const NUM_COLUMNS: Option<u32> = Some(7);
+/// For the journal of state pruning
+const COL_JOURNAL: &str = "col7";
+/// For the undo log of blocks
+const COL_UNDO: &str = "col8";
+pub const NUM_COLUMNS: Option<u32> = Some(9);
 
 pub fn map_columns(category: DataCategory) -> &'static str {
     match category {
@@ -26,5 +30,7 @@ pub fn map_columns(category: DataCategory) -> &'static str {
         DataCategory::Trace => COL_TRACE,
         DataCategory::AccountBloom => COL_ACCOUNT_BLOOM,
         DataCategory::Other => COL_OTHER,
+        DataCategory::Journal => COL_JOURNAL,
+        DataCategory::Undo => COL_UNDO,
     }
 }
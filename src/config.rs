@@ -1,3 +1,11 @@
+use std::collections::HashMap;
+
+use crate::database::{DataCategory, Result};
+use crate::error::DatabaseError;
+use crate::filter::CompactionFilter;
+use crate::merge::MergeOperator;
+use crate::ttl::Ttl;
+
 // Default config
 pub const BACKGROUND_FLUSHES: i32 = 2;
 pub const BACKGROUND_COMPACTIONS: i32 = 2;
@@ -17,6 +25,10 @@ pub struct Config {
     pub compaction: Compaction,
     /// Good value for total_threads is the number of cores.
     pub increase_parallelism: Option<i32>,
+    /// Configuration of each category, the default is used for the ones not set.
+    pub categories: HashMap<DataCategory, CategoryConfig>,
+    /// Maximum size of the LMDB map in bytes, a multiple of the page size.
+    pub map_size: usize,
 }
 
 impl Config {
@@ -27,6 +39,20 @@ impl Config {
             ..Default::default()
         }
     }
+
+    /// Get the configuration of a category.
+    pub fn category(&self, category: &DataCategory) -> CategoryConfig {
+        self.categories.get(category).cloned().unwrap_or_default()
+    }
+
+    // Fail with `InvalidData` if any category has a time-to-live, for the
+    // backends which do not expire the values.
+    pub(crate) fn check_no_ttl(&self) -> Result<()> {
+        if self.categories.values().any(|c| c.ttl.is_some()) {
+            return Err(DatabaseError::InvalidData);
+        }
+        Ok(())
+    }
 }
 
 impl Default for Config {
@@ -37,6 +63,50 @@ impl Default for Config {
             max_open_files: 512,
             compaction: Compaction::default(),
             increase_parallelism: None,
+            categories: HashMap::new(),
+            map_size: 1024 * 1024 * 1024,
+        }
+    }
+}
+
+/// Configuration of a category.
+#[derive(Clone, Default, Debug)]
+pub struct CategoryConfig {
+    /// Count the references of each key: insert adds one and remove drops one,
+    /// and the key is deleted when no longer referenced.
+    pub ref_counted: bool,
+    /// The merge operator of `Database::merge`, not used if it is ref-counted.
+    pub merge_operator: Option<MergeOperator>,
+    /// Expire the values after the time-to-live, not used if it is ref-counted
+    /// or has a merge operator. Only `RocksDB` applies it, and the other
+    /// backends fail to open with it.
+    pub ttl: Option<Ttl>,
+    /// The compaction of the category, `Config::compaction` if it is not set.
+    pub compaction: Option<Compaction>,
+    /// Decide whether to keep, remove or rewrite each key during compaction,
+    /// not used if it is ref-counted.
+    pub compaction_filter: Option<CompactionFilter>,
+    /// Store the large values in blob files out of the LSM tree.
+    pub blob: Option<BlobConfig>,
+}
+
+impl CategoryConfig {
+    // The merge operator used by the category.
+    pub(crate) fn merge_operator(&self) -> Option<MergeOperator> {
+        if self.ref_counted {
+            None
+        } else {
+            self.merge_operator
+        }
+    }
+
+    // The time-to-live used by the category.
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn ttl(&self) -> Option<&Ttl> {
+        if self.ref_counted || self.merge_operator.is_some() {
+            None
+        } else {
+            self.ttl.as_ref()
         }
     }
 }
@@ -46,8 +116,26 @@ pub struct Compaction {
     /// L0-L1 target file size
     pub target_file_size_base: u64,
     pub max_bytes_for_level_multiplier: Option<f64>,
-    /// Sets the maximum number of concurrent background compaction jobs
+    /// Sets the maximum number of concurrent background compaction jobs, of
+    /// the whole database, so it is only set in `Config::compaction`
     pub max_background_compactions: Option<i32>,
+    /// Compaction style
+    pub style: CompactionStyle,
+}
+
+/// The compaction style, with its size limits.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub enum CompactionStyle {
+    /// Level style, with the least space amplification.
+    #[default]
+    Level,
+    /// Universal style, with less write amplification for write-heavy data.
+    /// A full compaction runs when the size amplification is over the percent.
+    Universal { max_size_amplification_percent: i32 },
+    /// FIFO style for append-only data, which deletes the oldest files when
+    /// their total size is over the limit in bytes, so that data is lost.
+    /// It keeps all the files open, so `Config::max_open_files` must be -1.
+    Fifo { max_table_files_size: u64 },
 }
 
 impl Default for Compaction {
@@ -56,6 +144,42 @@ impl Default for Compaction {
             target_file_size_base: 64 * 1024 * 1024,
             max_bytes_for_level_multiplier: None,
             max_background_compactions: None,
+            style: CompactionStyle::Level,
         }
     }
 }
+
+/// Key-value separation of the large values, with RocksDB integrated BlobDB.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct BlobConfig {
+    /// Values of at least this size are stored in blob files
+    pub min_blob_size: u64,
+    /// Target size of blob files
+    pub blob_file_size: u64,
+    pub compression: BlobCompression,
+    /// Relocate the live blobs of the oldest files during compaction
+    pub enable_gc: bool,
+    /// The fraction of the oldest blob files relocated by the GC
+    pub gc_age_cutoff: f64,
+}
+
+impl Default for BlobConfig {
+    fn default() -> BlobConfig {
+        BlobConfig {
+            min_blob_size: 4 * 1024,
+            blob_file_size: 256 * 1024 * 1024,
+            compression: BlobCompression::None,
+            enable_gc: true,
+            gc_age_cutoff: 0.25,
+        }
+    }
+}
+
+/// The compression of blob files.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlobCompression {
+    None,
+    Snappy,
+    Lz4,
+    Zstd,
+}
@@ -1,12 +1,15 @@
+use crate::batch::{Batch, BatchOp};
 use crate::error::DatabaseError;
-use rocksdb::DBIterator;
 use std::result;
 
 pub type Result<T> = result::Result<T, DatabaseError>;
 
+/// Iterator over the keys and values of a category.
+pub type DBIterator<'a> = Box<dyn Iterator<Item = Result<(Box<[u8]>, Box<[u8]>)>> + 'a>;
+
 /// Specify the category of data stored, and users can store the data in a
 /// decentralized manner.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub enum DataCategory {
     // State
     State,
@@ -16,12 +19,16 @@ pub enum DataCategory {
     Bodies,
     // Extras: Block hash, receipt, and so on
     Extra,
-    // TBD. Traces
+    // Traces, which can expire with `CategoryConfig::ttl`
     Trace,
     // TBD. Empty accounts bloom filter
     AccountBloom,
     // Keep it for compatibility
     Other,
+    // Journal of the state changes for pruning
+    Journal,
+    // Undo log of the blocks for rollback
+    Undo,
 }
 
 pub trait Database: Send + Sync {
@@ -48,11 +55,154 @@ pub trait Database: Send + Sync {
 
     fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()>;
 
+    /// Merge the operand into the value of the key, with the merge operator of
+    /// the category, see `CategoryConfig::merge_operator`.
+    ///
+    /// Return `DatabaseError::InvalidData` if the category has no merge operator,
+    /// or the operand or the stored value is invalid for it. The default has no
+    /// merge operators.
+    fn merge(
+        &self,
+        _category: Option<DataCategory>,
+        _key: Vec<u8>,
+        _operand: Vec<u8>,
+    ) -> Result<()> {
+        Err(DatabaseError::InvalidData)
+    }
+
+    /// Set the key to the new value, `None` to remove it, if its current value
+    /// is the expected one, `None` for absent. Return whether it is swapped.
+    ///
+    /// It is atomic with the other conditional writes of the database.
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool>;
+
+    /// Insert the value if the key is absent. Return whether it is inserted.
+    fn insert_if_absent(
+        &self,
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<bool> {
+        self.compare_and_swap(category, &key, None, Some(value))
+    }
+
+    /// Apply the operations of the batch atomically.
+    ///
+    /// The default applies them one by one, which is not atomic, so a database
+    /// with atomic batches should implement it.
+    fn write(&self, batch: Batch) -> Result<()> {
+        for op in batch {
+            match op {
+                BatchOp::Insert {
+                    category,
+                    key,
+                    value,
+                } => self.insert(category, key, value)?,
+                BatchOp::Remove { category, key } => self.remove(category, &key)?,
+                BatchOp::Merge {
+                    category,
+                    key,
+                    operand,
+                } => self.merge(category, key, operand)?,
+            }
+        }
+        Ok(())
+    }
+
     fn restore(&mut self, new_db: &str) -> Result<()>;
 
-    // TODO Replace the DBIterator
-    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator>;
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>>;
 
     fn close(&mut self);
     fn flush(&self) -> Result<()>;
 }
+
+/// Forward to the boxed database, so that a `Box<dyn Database>` can be used
+/// where a database is expected.
+impl<D: Database + ?Sized> Database for Box<D> {
+    fn get(&self, category: Option<DataCategory>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        (**self).get(category, key)
+    }
+
+    fn get_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: &[Vec<u8>],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        (**self).get_batch(category, keys)
+    }
+
+    fn insert(&self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        (**self).insert(category, key, value)
+    }
+
+    fn insert_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        (**self).insert_batch(category, keys, values)
+    }
+
+    fn contains(&self, category: Option<DataCategory>, key: &[u8]) -> Result<bool> {
+        (**self).contains(category, key)
+    }
+
+    fn remove(&self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        (**self).remove(category, key)
+    }
+
+    fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()> {
+        (**self).remove_batch(category, keys)
+    }
+
+    fn merge(&self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        (**self).merge(category, key, operand)
+    }
+
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        (**self).compare_and_swap(category, key, expected, new)
+    }
+
+    fn insert_if_absent(
+        &self,
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+        value: Vec<u8>,
+    ) -> Result<bool> {
+        (**self).insert_if_absent(category, key, value)
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        (**self).write(batch)
+    }
+
+    fn restore(&mut self, new_db: &str) -> Result<()> {
+        (**self).restore(new_db)
+    }
+
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
+        (**self).iterator(category)
+    }
+
+    fn close(&mut self) {
+        (**self).close()
+    }
+
+    fn flush(&self) -> Result<()> {
+        (**self).flush()
+    }
+}
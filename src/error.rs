@@ -1,4 +1,9 @@
+#[cfg(feature = "lmdb")]
+use heed::Error as HeedError;
+#[cfg(feature = "rocksdb")]
 use rocksdb::Error as RocksError;
+#[cfg(feature = "sqlite")]
+use rusqlite::Error as SqliteError;
 use std::error::Error;
 use std::fmt;
 use std::io::Error as IOError;
@@ -7,6 +12,10 @@ use std::io::Error as IOError;
 pub enum DatabaseError {
     NotFound,
     InvalidData,
+    // Another writer changed the data read by the transaction
+    Conflict,
+    // Write to a database opened read-only or as a secondary
+    ReadOnly,
     Internal(String),
 }
 
@@ -16,18 +25,43 @@ impl From<IOError> for DatabaseError {
     }
 }
 
+#[cfg(feature = "rocksdb")]
 impl From<RocksError> for DatabaseError {
     fn from(err: RocksError) -> Self {
         DatabaseError::Internal(err.to_string())
     }
 }
 
+#[cfg(feature = "lmdb")]
+impl From<HeedError> for DatabaseError {
+    fn from(err: HeedError) -> Self {
+        DatabaseError::Internal(err.to_string())
+    }
+}
+
+#[cfg(feature = "sqlite")]
+impl From<SqliteError> for DatabaseError {
+    fn from(err: SqliteError) -> Self {
+        DatabaseError::Internal(err.to_string())
+    }
+}
+
+pub(crate) fn map_rwlock_err() -> DatabaseError {
+    DatabaseError::Internal("rwlock error".to_string())
+}
+
+pub(crate) fn map_mutex_err() -> DatabaseError {
+    DatabaseError::Internal("mutex error".to_string())
+}
+
 impl Error for DatabaseError {}
 impl fmt::Display for DatabaseError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let printable = match *self {
             DatabaseError::NotFound => "not found".to_owned(),
             DatabaseError::InvalidData => "invalid data".to_owned(),
+            DatabaseError::Conflict => "transaction conflict".to_owned(),
+            DatabaseError::ReadOnly => "read-only database".to_owned(),
             DatabaseError::Internal(ref err) => format!("internal error: {:?}", err),
         };
         write!(f, "{}", printable)
@@ -0,0 +1,234 @@
+use std::io::{Read, Write};
+
+use crate::database::{DataCategory, Result};
+use crate::error::DatabaseError;
+use crc32fast::Hasher;
+
+// The header of a dump file.
+const MAGIC: &[u8; 8] = b"CITADUMP";
+const VERSION: u8 = 1;
+// Marks the end of the entries. It is followed by the number of entries and the checksum.
+const END: u8 = 0xff;
+
+/// An exported key/value pair with its data category.
+pub type Entry = (Option<DataCategory>, Vec<u8>, Vec<u8>);
+
+/// Write the entries in the dump format.
+///
+/// The dump is backend independent, so a dump saved from one database can be
+/// loaded into another.
+pub fn write_entries<W, I>(writer: W, entries: I) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = Entry>,
+{
+    let mut writer = ChecksumWriter::new(writer);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&[VERSION])?;
+
+    let mut count: u64 = 0;
+    for (category, key, value) in entries {
+        writer.write_all(&[category_to_tag(&category)])?;
+        write_bytes(&mut writer, &key)?;
+        write_bytes(&mut writer, &value)?;
+        count += 1;
+    }
+
+    writer.write_all(&[END])?;
+    writer.write_all(&count.to_le_bytes())?;
+    let checksum = writer.hasher.clone().finalize();
+    writer.inner.write_all(&checksum.to_le_bytes())?;
+    writer.inner.flush()?;
+    Ok(())
+}
+
+/// Read all the entries of a dump, verifying its checksum.
+pub fn read_entries<R: Read>(reader: R) -> Result<Vec<Entry>> {
+    let mut reader = ChecksumReader::new(reader);
+
+    let mut magic = [0u8; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC || read_u8(&mut reader)? != VERSION {
+        return Err(DatabaseError::InvalidData);
+    }
+
+    let mut entries = Vec::new();
+    loop {
+        let tag = read_u8(&mut reader)?;
+        if tag == END {
+            break;
+        }
+        let category = tag_to_category(tag)?;
+        let key = read_bytes(&mut reader)?;
+        let value = read_bytes(&mut reader)?;
+        entries.push((category, key, value));
+    }
+
+    let mut count = [0u8; 8];
+    reader.read_exact(&mut count)?;
+    let expected = reader.hasher.clone().finalize();
+    let mut checksum = [0u8; 4];
+    reader.inner.read_exact(&mut checksum)?;
+    if u32::from_le_bytes(checksum) != expected || u64::from_le_bytes(count) != entries.len() as u64
+    {
+        return Err(DatabaseError::InvalidData);
+    }
+
+    Ok(entries)
+}
+
+pub(crate) fn category_to_tag(category: &Option<DataCategory>) -> u8 {
+    match category {
+        None => 0,
+        Some(DataCategory::State) => 1,
+        Some(DataCategory::Headers) => 2,
+        Some(DataCategory::Bodies) => 3,
+        Some(DataCategory::Extra) => 4,
+        Some(DataCategory::Trace) => 5,
+        Some(DataCategory::AccountBloom) => 6,
+        Some(DataCategory::Other) => 7,
+        Some(DataCategory::Journal) => 8,
+        Some(DataCategory::Undo) => 9,
+    }
+}
+
+pub(crate) fn tag_to_category(tag: u8) -> Result<Option<DataCategory>> {
+    let category = match tag {
+        0 => None,
+        1 => Some(DataCategory::State),
+        2 => Some(DataCategory::Headers),
+        3 => Some(DataCategory::Bodies),
+        4 => Some(DataCategory::Extra),
+        5 => Some(DataCategory::Trace),
+        6 => Some(DataCategory::AccountBloom),
+        7 => Some(DataCategory::Other),
+        8 => Some(DataCategory::Journal),
+        9 => Some(DataCategory::Undo),
+        _ => return Err(DatabaseError::InvalidData),
+    };
+    Ok(category)
+}
+
+pub(crate) fn write_bytes<W: Write>(writer: &mut W, bytes: &[u8]) -> Result<()> {
+    let len = u32::try_from(bytes.len()).map_err(|_| DatabaseError::InvalidData)?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(bytes)?;
+    Ok(())
+}
+
+// Allocate as the bytes are read, not by the length which may be corrupted.
+pub(crate) fn read_bytes<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    let len = u64::from(u32::from_le_bytes(len));
+    let mut bytes = Vec::new();
+    if reader.take(len).read_to_end(&mut bytes)? as u64 != len {
+        return Err(DatabaseError::InvalidData);
+    }
+    Ok(bytes)
+}
+
+pub(crate) fn read_u8<R: Read>(reader: &mut R) -> Result<u8> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    Ok(byte[0])
+}
+
+// Checksum everything written through it.
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> ChecksumWriter<W> {
+    fn new(inner: W) -> Self {
+        ChecksumWriter {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+}
+
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Checksum everything read through it.
+struct ChecksumReader<R> {
+    inner: R,
+    hasher: Hasher,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    fn new(inner: R) -> Self {
+        ChecksumReader {
+            inner,
+            hasher: Hasher::new(),
+        }
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_entries, write_entries};
+    use crate::database::DataCategory;
+    use crate::error::DatabaseError;
+
+    #[test]
+    fn test_write_read_entries() {
+        let entries = vec![
+            (None, b"key".to_vec(), b"value".to_vec()),
+            (
+                Some(DataCategory::State),
+                b"key".to_vec(),
+                b"state".to_vec(),
+            ),
+            (Some(DataCategory::Other), vec![], vec![]),
+        ];
+
+        let mut dump = Vec::new();
+        write_entries(&mut dump, entries.clone()).unwrap();
+
+        assert_eq!(read_entries(&dump[..]), Ok(entries));
+    }
+
+    #[test]
+    fn test_read_corrupted_entries() {
+        let mut dump = Vec::new();
+        write_entries(&mut dump, vec![(None, b"key".to_vec(), b"value".to_vec())]).unwrap();
+
+        // Flip a byte of the value.
+        let pos = dump.len() - 15;
+        dump[pos] ^= 0x01;
+
+        assert_eq!(read_entries(&dump[..]), Err(DatabaseError::InvalidData));
+    }
+
+    #[test]
+    fn test_read_truncated_entries() {
+        let mut dump = Vec::new();
+        write_entries(&mut dump, vec![(None, b"key".to_vec(), b"value".to_vec())]).unwrap();
+
+        // Corrupt the length of the key to the maximum, and cut the dump there.
+        dump.truncate(14);
+        dump[10..14].copy_from_slice(&u32::MAX.to_le_bytes());
+
+        assert_eq!(read_entries(&dump[..]), Err(DatabaseError::InvalidData));
+    }
+}
@@ -0,0 +1,160 @@
+//! Open a database from a URI-style spec, `scheme://path`, so that the backend
+//! can be chosen by the config file.
+//!
+//! The built-in schemes are `memory`, and with their features, `rocksdb`,
+//! `rocksdb+readonly`, `lmdb` and `sqlite`. More can be added with
+//! `register_scheme`.
+
+use std::collections::HashMap;
+use std::sync::{Arc, OnceLock, RwLock};
+
+use crate::config::Config;
+use crate::database::{Database, Result};
+use crate::error::{map_rwlock_err, DatabaseError};
+use crate::memorydb::MemoryDB;
+
+const SCHEME_SEPARATOR: &str = "://";
+
+// Open a database at the path of the spec, with the config.
+type OpenFn = dyn Fn(&str, &Config) -> Result<Box<dyn Database>> + Send + Sync;
+
+static REGISTRY: OnceLock<RwLock<HashMap<String, Arc<OpenFn>>>> = OnceLock::new();
+
+/// Open the database of the spec, like `rocksdb:///data/statedb` or `memory://`.
+///
+/// Return `DatabaseError::InvalidData` if the spec is not `scheme://path`, and
+/// `DatabaseError::NotFound` if the scheme is not registered.
+pub fn open_database(spec: &str, config: &Config) -> Result<Box<dyn Database>> {
+    let (scheme, path) = spec
+        .split_once(SCHEME_SEPARATOR)
+        .ok_or(DatabaseError::InvalidData)?;
+    let open = registry()
+        .read()
+        .map_err(|_| map_rwlock_err())?
+        .get(scheme)
+        .cloned()
+        .ok_or(DatabaseError::NotFound)?;
+    open(path, config)
+}
+
+/// Register how to open the databases of the scheme, replacing the one
+/// registered before.
+pub fn register_scheme<F>(scheme: &str, open: F) -> Result<()>
+where
+    F: Fn(&str, &Config) -> Result<Box<dyn Database>> + Send + Sync + 'static,
+{
+    registry()
+        .write()
+        .map_err(|_| map_rwlock_err())?
+        .insert(scheme.to_owned(), Arc::new(open));
+    Ok(())
+}
+
+fn registry() -> &'static RwLock<HashMap<String, Arc<OpenFn>>> {
+    REGISTRY.get_or_init(|| RwLock::new(builtin_schemes()))
+}
+
+fn builtin_schemes() -> HashMap<String, Arc<OpenFn>> {
+    let mut schemes: HashMap<String, Arc<OpenFn>> = HashMap::new();
+    schemes.insert(
+        "memory".to_owned(),
+        Arc::new(|_, config| Ok(Box::new(MemoryDB::with_config(config)?))),
+    );
+    #[cfg(feature = "rocksdb")]
+    {
+        use crate::rocksdb::RocksDB;
+        schemes.insert(
+            "rocksdb".to_owned(),
+            Arc::new(|path, config| Ok(Box::new(RocksDB::open(path, config)?))),
+        );
+        schemes.insert(
+            "rocksdb+readonly".to_owned(),
+            Arc::new(|path, config| Ok(Box::new(RocksDB::open_read_only(path, config)?))),
+        );
+    }
+    #[cfg(feature = "lmdb")]
+    schemes.insert(
+        "lmdb".to_owned(),
+        Arc::new(|path, config| Ok(Box::new(crate::lmdb::LmdbDB::open(path, config)?))),
+    );
+    #[cfg(feature = "sqlite")]
+    schemes.insert(
+        "sqlite".to_owned(),
+        Arc::new(|path, config| Ok(Box::new(crate::sqlite::SqliteDB::open(path, config)?))),
+    );
+    schemes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{open_database, register_scheme};
+    use crate::config::Config;
+    use crate::database::{DataCategory, Database};
+    use crate::error::DatabaseError;
+    use crate::memorydb::MemoryDB;
+    use crate::overlaydb::OverlayDB;
+    use crate::test::{insert_get_contains_remove, merge, merge_config};
+
+    #[test]
+    fn test_open_memory() {
+        let db = open_database("memory://", &merge_config()).unwrap();
+
+        insert_get_contains_remove(&db, Some(DataCategory::State));
+        merge(&db);
+
+        // Wrap the boxed database.
+        let overlay = OverlayDB::new(db);
+        overlay
+            .insert(None, b"test".to_vec(), b"test".to_vec())
+            .unwrap();
+        overlay.commit().unwrap();
+    }
+
+    #[test]
+    fn test_invalid_spec() {
+        let config = Config::default();
+
+        assert!(matches!(
+            open_database("memory", &config),
+            Err(DatabaseError::InvalidData)
+        ));
+        assert!(matches!(
+            open_database("unknown://test", &config),
+            Err(DatabaseError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn test_register_scheme() {
+        register_scheme("test+memory", |path, _| {
+            assert_eq!(path, "test");
+            Ok(Box::new(MemoryDB::open()))
+        })
+        .unwrap();
+
+        let db = open_database("test+memory://test", &Config::default()).unwrap();
+        insert_get_contains_remove(&db, None);
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn test_open_rocksdb() {
+        let path = "rocksdb_test/factory_open";
+        let config = Config::with_category_num(crate::columns::NUM_COLUMNS);
+        let data = b"test".to_vec();
+        {
+            let db = open_database(&format!("rocksdb://{}", path), &config).unwrap();
+            db.insert(Some(DataCategory::State), data.clone(), data.clone())
+                .unwrap();
+        }
+
+        let db = open_database(&format!("rocksdb+readonly://{}", path), &config).unwrap();
+        assert_eq!(db.get(Some(DataCategory::State), &data), Ok(Some(data)));
+        assert_eq!(
+            db.insert(None, b"test".to_vec(), b"test".to_vec()),
+            Err(DatabaseError::ReadOnly)
+        );
+        drop(db);
+        std::fs::remove_dir_all(path).unwrap();
+    }
+}
@@ -0,0 +1,236 @@
+//! User compaction filters of the categories.
+
+#[cfg(feature = "rocksdb")]
+use std::ffi::{CStr, CString};
+use std::fmt;
+use std::sync::Arc;
+
+#[cfg(feature = "rocksdb")]
+use crate::{config::CategoryConfig, refcount, ttl, ttl::Ttl};
+#[cfg(feature = "rocksdb")]
+use rocksdb::{
+    compaction_filter::CompactionFilter as RocksFilter,
+    compaction_filter_factory::{CompactionFilterContext, CompactionFilterFactory},
+    CompactionDecision, Options,
+};
+
+#[cfg(feature = "rocksdb")]
+const COMPACTION_FILTER_NAME: &str = "category_filter";
+
+/// The decision of a compaction filter on a key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterDecision {
+    Keep,
+    Remove,
+    /// Rewrite the value.
+    Change(Vec<u8>),
+}
+
+// The callback of a user compaction filter.
+type FilterFn = dyn Fn(&[u8], &[u8]) -> FilterDecision + Send + Sync;
+
+/// A callback called with each key and value of the category during compaction.
+///
+/// It may be called from several compactions at the same time.
+#[derive(Clone)]
+#[cfg_attr(not(feature = "rocksdb"), allow(dead_code))]
+pub struct CompactionFilter(Arc<FilterFn>);
+
+impl CompactionFilter {
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&[u8], &[u8]) -> FilterDecision + Send + Sync + 'static,
+    {
+        CompactionFilter(Arc::new(filter))
+    }
+}
+
+impl fmt::Debug for CompactionFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("CompactionFilter")
+    }
+}
+
+/// Set the compaction filter of the column of a category.
+///
+/// The built-in filters run first, and the user filter sees the values as
+/// they are read. It is not used for the ref-counted categories.
+#[cfg(feature = "rocksdb")]
+pub(crate) fn set_column_filter(opts: &mut Options, config: &CategoryConfig) {
+    if config.ref_counted {
+        opts.set_compaction_filter(
+            refcount::COMPACTION_FILTER_NAME,
+            refcount::compaction_filter,
+        );
+        return;
+    }
+
+    let ttl = config.ttl().cloned();
+    match config.compaction_filter.clone() {
+        Some(filter) => {
+            opts.set_compaction_filter_factory(ColumnFilterFactory(ColumnFilter::new(ttl, filter)))
+        }
+        None => {
+            if let Some(ttl) = ttl {
+                opts.set_compaction_filter(ttl::COMPACTION_FILTER_NAME, ttl.compaction_filter());
+            }
+        }
+    }
+}
+
+// The user filter of a column, with the time-to-live of the values.
+#[cfg(feature = "rocksdb")]
+#[derive(Clone)]
+pub(crate) struct ColumnFilter {
+    name: CString,
+    ttl: Option<Ttl>,
+    filter: CompactionFilter,
+    // The last changed value, which RocksDB copies before the next call.
+    changed: Vec<u8>,
+}
+
+#[cfg(feature = "rocksdb")]
+impl ColumnFilter {
+    fn new(ttl: Option<Ttl>, filter: CompactionFilter) -> Self {
+        ColumnFilter {
+            name: CString::new(COMPACTION_FILTER_NAME).expect("no nul in the name"),
+            ttl,
+            filter,
+            changed: Vec::new(),
+        }
+    }
+
+    fn decide(&mut self, key: &[u8], stored: &[u8]) -> CompactionDecision {
+        let (value, suffix) = match self.ttl {
+            Some(ref ttl) => match (ttl.is_expired(stored), ttl::split(stored)) {
+                (Ok(true), _) => return CompactionDecision::Remove,
+                (Ok(false), Ok(split)) => split,
+                _ => return CompactionDecision::Keep,
+            },
+            None => (stored, &[][..]),
+        };
+
+        match (self.filter.0)(key, value) {
+            FilterDecision::Keep => CompactionDecision::Keep,
+            FilterDecision::Remove => CompactionDecision::Remove,
+            FilterDecision::Change(mut value) => {
+                value.extend_from_slice(suffix);
+                if value == stored {
+                    return CompactionDecision::Keep;
+                }
+                self.changed = value;
+                // SAFETY: RocksDB takes the changed value as static, but copies
+                // it as soon as the filter returns, before the next call replaces
+                // it. The filter is only used by the thread of its compaction.
+                let changed = unsafe {
+                    std::slice::from_raw_parts(self.changed.as_ptr(), self.changed.len())
+                };
+                CompactionDecision::Change(changed)
+            }
+        }
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl RocksFilter for ColumnFilter {
+    fn filter(&mut self, _level: u32, key: &[u8], value: &[u8]) -> CompactionDecision {
+        self.decide(key, value)
+    }
+
+    fn name(&self) -> &CStr {
+        &self.name
+    }
+}
+
+// Create a filter for each compaction, which is only used by its thread.
+#[cfg(feature = "rocksdb")]
+struct ColumnFilterFactory(ColumnFilter);
+
+#[cfg(feature = "rocksdb")]
+impl CompactionFilterFactory for ColumnFilterFactory {
+    type Filter = ColumnFilter;
+
+    fn create(&mut self, _context: CompactionFilterContext) -> ColumnFilter {
+        self.0.clone()
+    }
+
+    fn name(&self) -> &CStr {
+        &self.0.name
+    }
+}
+
+#[cfg(all(test, feature = "rocksdb"))]
+mod tests {
+    use super::{ColumnFilter, CompactionFilter, FilterDecision};
+    use crate::ttl::{HeightClock, Ttl};
+    use rocksdb::compaction_filter::CompactionFilter as RocksFilter;
+    use rocksdb::CompactionDecision;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_column_filter_with_ttl() {
+        let clock = Arc::new(HeightClock::new(1));
+        let ttl = Ttl::blocks(2, Arc::clone(&clock));
+        let mut filter = ColumnFilter::new(
+            Some(ttl.clone()),
+            CompactionFilter::new(|key, value| match key {
+                b"remove" => FilterDecision::Remove,
+                b"change" => FilterDecision::Change([value, b"-changed"].concat()),
+                _ => FilterDecision::Keep,
+            }),
+        );
+        let stored = ttl.encode(b"test");
+
+        match filter.filter(0, b"keep", &stored) {
+            CompactionDecision::Keep => (), // pass
+            _ => panic!("should keep the value"),
+        }
+        match filter.filter(0, b"remove", &stored) {
+            CompactionDecision::Remove => (), // pass
+            _ => panic!("should remove the value"),
+        }
+        match filter.filter(0, b"change", &stored) {
+            CompactionDecision::Change(changed) => {
+                assert_eq!(
+                    ttl.decode(changed.to_vec()),
+                    Ok(Some(b"test-changed".to_vec()))
+                )
+            }
+            _ => panic!("should change the value"),
+        }
+
+        // Expired
+        clock.set(3);
+        match filter.filter(0, b"keep", &stored) {
+            CompactionDecision::Remove => (), // pass
+            _ => panic!("should remove the expired value"),
+        }
+    }
+
+    #[test]
+    fn test_change_to_same_value() {
+        let mut filter = ColumnFilter::new(
+            None,
+            CompactionFilter::new(|_key, value| FilterDecision::Change(value.to_vec())),
+        );
+        match filter.filter(0, b"key", b"test") {
+            CompactionDecision::Keep => (), // pass
+            _ => panic!("should keep the same value"),
+        }
+    }
+    #[test]
+    fn test_change_values() {
+        let mut filter = ColumnFilter::new(
+            None,
+            CompactionFilter::new(|_key, value| FilterDecision::Change([value, value].concat())),
+        );
+        // Each changed value is valid until the next call.
+        for value in [&b"a"[..], b"bc", b""] {
+            match filter.filter(0, b"key", value) {
+                CompactionDecision::Change(changed) => assert_eq!(changed, [value, value].concat()),
+                CompactionDecision::Keep if value.is_empty() => (), // pass
+                _ => panic!("should change the value"),
+            }
+        }
+    }
+}
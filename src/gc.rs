@@ -0,0 +1,289 @@
+use std::collections::HashSet;
+
+use crate::database::{DataCategory, Database, Result};
+use crate::error::DatabaseError;
+
+// The key of the last swept state key, to resume an interrupted collection.
+const CURSOR_KEY: &[u8] = b"gc-cursor";
+// Default number of keys deleted in a batch.
+const BATCH_SIZE: usize = 10_000;
+
+/// Progress of a garbage collection.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct GcProgress {
+    /// Number of reachable nodes marked
+    pub marked: u64,
+    /// Number of state keys swept
+    pub swept: u64,
+    /// Number of unreachable nodes deleted
+    pub removed: u64,
+}
+
+/// Mark-and-sweep garbage collection of `DataCategory::State`.
+///
+/// Every node reachable from the live state roots is marked, using the decode
+/// callback to get the keys of the children of a node. Then all the other keys
+/// are deleted in batches. The sweep position is saved in `DataCategory::Journal`
+/// after each batch, so an interrupted collection resumes from there.
+///
+/// The database must support `Database::iterator`, and the state should not be
+/// ref-counted. The marked keys are kept in memory, so it needs about the size
+/// of the keys of the live state. When running online, a node referenced again by a new block
+/// after the marking can still be deleted, so block import should be paused.
+pub struct StateGc<F> {
+    decode: F,
+    batch_size: usize,
+}
+
+impl<F> StateGc<F>
+where
+    F: Fn(&[u8]) -> Vec<Vec<u8>>,
+{
+    /// Create it with the callback that decodes a node and returns the keys of its children.
+    pub fn new(decode: F) -> Self {
+        StateGc {
+            decode,
+            batch_size: BATCH_SIZE,
+        }
+    }
+
+    /// Set the number of keys deleted in a batch.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size.max(1);
+        self
+    }
+
+    /// Collect the nodes unreachable from the roots.
+    ///
+    /// The progress callback is called after each batch of the marking and the sweep.
+    pub fn run<D, P>(&self, db: &D, roots: &[Vec<u8>], mut progress: P) -> Result<GcProgress>
+    where
+        D: Database,
+        P: FnMut(&GcProgress),
+    {
+        let mut stats = GcProgress::default();
+        let marked = self.mark(db, roots, &mut stats, &mut progress)?;
+        self.sweep(db, &marked, &mut stats, &mut progress)?;
+
+        Ok(stats)
+    }
+
+    fn mark<D, P>(
+        &self,
+        db: &D,
+        roots: &[Vec<u8>],
+        stats: &mut GcProgress,
+        progress: &mut P,
+    ) -> Result<HashSet<Vec<u8>>>
+    where
+        D: Database,
+        P: FnMut(&GcProgress),
+    {
+        let mut marked = HashSet::new();
+        let mut pending = roots.to_vec();
+
+        while let Some(key) = pending.pop() {
+            if marked.contains(&key) {
+                continue;
+            }
+            let node = match db.get(Some(DataCategory::State), &key)? {
+                Some(node) => node,
+                // Missing nodes are not marked
+                None => continue,
+            };
+            pending.extend((self.decode)(&node));
+            marked.insert(key);
+
+            stats.marked += 1;
+            if stats.marked.is_multiple_of(self.batch_size as u64) {
+                progress(stats);
+            }
+        }
+        progress(stats);
+
+        Ok(marked)
+    }
+
+    fn sweep<D, P>(
+        &self,
+        db: &D,
+        marked: &HashSet<Vec<u8>>,
+        stats: &mut GcProgress,
+        progress: &mut P,
+    ) -> Result<()>
+    where
+        D: Database,
+        P: FnMut(&GcProgress),
+    {
+        let cursor = db.get(Some(DataCategory::Journal), CURSOR_KEY)?;
+        let iter = db
+            .iterator(Some(DataCategory::State))
+            .ok_or(DatabaseError::NotFound)?;
+
+        let mut unreachable = Vec::new();
+        let mut last = None;
+        for item in iter {
+            let (key, _) = item?;
+            // Swept before the interruption
+            if cursor.as_deref().is_some_and(|cursor| &*key <= cursor) {
+                continue;
+            }
+            stats.swept += 1;
+            if !marked.contains(&*key) {
+                unreachable.push(key.to_vec());
+            }
+            last = Some(key);
+
+            if stats.swept.is_multiple_of(self.batch_size as u64) {
+                self.remove(db, &mut unreachable, last.as_deref(), stats)?;
+                progress(stats);
+            }
+        }
+        self.remove(db, &mut unreachable, last.as_deref(), stats)?;
+        progress(stats);
+
+        // Done, start from the beginning next time.
+        db.remove(Some(DataCategory::Journal), CURSOR_KEY)
+    }
+
+    // Delete the unreachable keys, and save the position of the sweep.
+    fn remove<D: Database>(
+        &self,
+        db: &D,
+        unreachable: &mut Vec<Vec<u8>>,
+        last: Option<&[u8]>,
+        stats: &mut GcProgress,
+    ) -> Result<()> {
+        db.remove_batch(Some(DataCategory::State), unreachable)?;
+        stats.removed += unreachable.len() as u64;
+        unreachable.clear();
+
+        if let Some(last) = last {
+            db.insert(
+                Some(DataCategory::Journal),
+                CURSOR_KEY.to_vec(),
+                last.to_vec(),
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GcProgress, StateGc, CURSOR_KEY};
+    use crate::database::{DataCategory, Database};
+    use crate::memorydb::MemoryDB;
+
+    // A node is the keys of its children joined by ','.
+    fn decode(node: &[u8]) -> Vec<Vec<u8>> {
+        node.split(|b| *b == b',')
+            .filter(|key| !key.is_empty())
+            .map(|key| key.to_vec())
+            .collect()
+    }
+
+    fn insert_nodes<D: Database>(db: &D, nodes: &[(&str, &str)]) {
+        let (keys, values) = nodes
+            .iter()
+            .map(|(key, node)| (key.as_bytes().to_vec(), node.as_bytes().to_vec()))
+            .unzip();
+        db.insert_batch(Some(DataCategory::State), keys, values)
+            .unwrap();
+    }
+
+    fn contains<D: Database>(db: &D, key: &str) -> bool {
+        db.contains(Some(DataCategory::State), key.as_bytes())
+            .unwrap()
+    }
+
+    fn mark_and_sweep<D: Database>(db: &D) {
+        insert_nodes(
+            db,
+            &[
+                ("root", "a,b"),
+                ("a", "c,missing"),
+                ("b", "c"),
+                ("c", ""),
+                ("old", "x"),
+                ("x", ""),
+            ],
+        );
+        // Not swept
+        db.insert(Some(DataCategory::Extra), b"old".to_vec(), vec![])
+            .unwrap();
+
+        let mut reports = 0;
+        let stats = StateGc::new(decode)
+            .batch_size(2)
+            .run(db, &[b"root".to_vec()], |_| reports += 1)
+            .unwrap();
+
+        assert_eq!(
+            stats,
+            GcProgress {
+                marked: 4,
+                swept: 6,
+                removed: 2,
+            }
+        );
+        assert!(reports > 2);
+        for key in ["root", "a", "b", "c"].iter() {
+            assert!(contains(db, key));
+        }
+        assert!(!contains(db, "old"));
+        assert!(!contains(db, "x"));
+        assert_eq!(db.contains(Some(DataCategory::Extra), b"old"), Ok(true));
+        assert_eq!(
+            db.contains(Some(DataCategory::Journal), CURSOR_KEY),
+            Ok(false)
+        );
+    }
+
+    fn resume<D: Database>(db: &D) {
+        insert_nodes(db, &[("a", ""), ("b", ""), ("c", "")]);
+        // Interrupted after sweeping "a".
+        db.insert(
+            Some(DataCategory::Journal),
+            CURSOR_KEY.to_vec(),
+            b"a".to_vec(),
+        )
+        .unwrap();
+
+        let stats = StateGc::new(decode).run(db, &[], |_| ()).unwrap();
+
+        assert_eq!(stats.swept, 2);
+        assert!(contains(db, "a"));
+        assert!(!contains(db, "b"));
+        assert!(!contains(db, "c"));
+    }
+
+    #[test]
+    fn test_mark_and_sweep() {
+        mark_and_sweep(&MemoryDB::open());
+    }
+
+    #[test]
+    fn test_resume() {
+        resume(&MemoryDB::open());
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn test_rocksdb() {
+        use crate::columns::NUM_COLUMNS;
+        use crate::config::Config;
+        use crate::rocksdb::RocksDB;
+
+        let cfg = Config::with_category_num(NUM_COLUMNS);
+        let mut db = RocksDB::open("rocksdb_test/gc_mark_and_sweep", &cfg).unwrap();
+        mark_and_sweep(&db);
+        db.clean_cf();
+        db.clean_db();
+
+        let mut db = RocksDB::open("rocksdb_test/gc_resume", &cfg).unwrap();
+        resume(&db);
+        db.clean_cf();
+        db.clean_db();
+    }
+}
@@ -1,9 +1,34 @@
+#[cfg(feature = "async")]
+pub mod asyncdb;
+pub mod batch;
+pub mod changefeed;
 pub mod columns;
 pub mod config;
 pub mod database;
 pub mod error;
+pub mod export;
+pub mod factory;
+pub mod filter;
+pub mod gc;
+#[cfg(feature = "lmdb")]
+pub mod lmdb;
 pub mod memorydb;
+pub mod merge;
+pub mod overlaydb;
+pub mod pruning;
+pub mod remotedb;
+#[cfg(feature = "rocksdb")]
 pub mod rocksdb;
+pub mod server;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod transaction;
+pub mod ttl;
+pub mod undo;
+
+mod protocol;
+#[cfg(feature = "rocksdb")]
+mod refcount;
 
 #[cfg(test)]
 pub(crate) mod test;
@@ -11,9 +36,31 @@ pub(crate) mod test;
 #[macro_use]
 extern crate cita_logger as logger;
 
+#[cfg(feature = "async")]
+pub use self::asyncdb::{AsyncDB, AsyncDatabase};
+pub use self::batch::{Batch, BatchOp, IndexedBatch};
+pub use self::changefeed::{Event, ObservableDB, Watch};
 pub use self::columns::NUM_COLUMNS;
 pub use self::config::Config;
 pub use self::database::{DataCategory, Database};
 pub use self::error::DatabaseError;
-pub use self::memorydb::MemoryDB;
+pub use self::factory::{open_database, register_scheme};
+pub use self::filter::{CompactionFilter, FilterDecision};
+pub use self::gc::{GcProgress, StateGc};
+#[cfg(feature = "lmdb")]
+pub use self::lmdb::LmdbDB;
+pub use self::memorydb::{MemoryDB, MemoryTransaction};
+pub use self::merge::MergeOperator;
+pub use self::overlaydb::OverlayDB;
+pub use self::pruning::{PruningConfig, StatePruner};
+pub use self::remotedb::RemoteDB;
+#[cfg(feature = "rocksdb")]
 pub use self::rocksdb::RocksDB;
+pub use self::server::Server;
+#[cfg(feature = "sqlite")]
+pub use self::sqlite::SqliteDB;
+pub use self::transaction::Transaction;
+#[cfg(feature = "rocksdb")]
+pub use self::transaction::{OptimisticDB, OptimisticTransaction};
+pub use self::ttl::{Clock, HeightClock, SystemClock, Ttl};
+pub use self::undo::UndoLog;
@@ -0,0 +1,538 @@
+use std::collections::{HashMap, VecDeque};
+use std::fs::{create_dir_all, metadata, remove_dir_all, rename};
+use std::ops::Bound;
+use std::path::Path;
+
+use crate::batch::{Batch, BatchOp};
+use crate::columns::map_columns;
+use crate::config::Config;
+use crate::database::{DBIterator, DataCategory, Database, Result};
+use crate::error::DatabaseError;
+use crate::merge::MergeOperator;
+use heed::types::Bytes;
+use heed::{Env, EnvOpenOptions, RoTxn, RwTxn};
+
+// The backup db path.
+const BACKUP_PATH: &str = "backup_old_lmdb";
+// The sub-database of the data without category.
+const DEFAULT_DB: &str = "default";
+// The number of entries the iterator reads in a transaction.
+const ITER_CHUNK: usize = 1024;
+
+type Db = heed::Database<Bytes, Bytes>;
+// A key and its value.
+type Entry = (Box<[u8]>, Box<[u8]>);
+
+/// LMDB implementation of the database, with a named sub-database for each
+/// category.
+///
+/// The merge operators of the categories in the config are used, the other
+/// category options are specific to RocksDB. The writes are serialized by
+/// LMDB, so the conditional writes are atomic.
+pub struct LmdbDB {
+    // `None` if it is closed.
+    env: Option<Env>,
+    // The sub-databases by name, see `map_columns`.
+    dbs: HashMap<String, Db>,
+    config: Config,
+    path: String,
+}
+
+impl LmdbDB {
+    /// Open a LMDB with default config.
+    pub fn open_default(path: &str) -> Result<Self> {
+        Self::open(path, &Config::default())
+    }
+
+    /// Open LMDB with config, creating the directory if it does not exist.
+    ///
+    /// A sub-database is created for each of `config.category_num` categories.
+    /// Return `DatabaseError::InvalidData` if a category has a time-to-live,
+    /// which it does not apply.
+    pub fn open(path: &str, config: &Config) -> Result<Self> {
+        config.check_no_ttl()?;
+        let category_num = config.category_num.unwrap_or(0);
+        create_dir_all(path)?;
+        // The files are only modified through LMDB.
+        let env = unsafe {
+            EnvOpenOptions::new()
+                .map_size(config.map_size)
+                .max_dbs(category_num + 1)
+                .open(path)?
+        };
+
+        let names = (0..category_num).map(|c| format!("col{}", c));
+        let mut txn = env.write_txn()?;
+        let mut dbs = HashMap::new();
+        for name in names.chain(Some(DEFAULT_DB.to_owned())) {
+            let db = env.create_database(&mut txn, Some(&name))?;
+            dbs.insert(name, db);
+        }
+        txn.commit()?;
+
+        Ok(LmdbDB {
+            env: Some(env),
+            dbs,
+            config: config.clone(),
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn close(&mut self) {
+        if let Some(env) = self.env.take() {
+            env.prepare_for_closing().wait();
+        }
+    }
+
+    /// Restore the database from a copy at given path.
+    pub fn restore(&mut self, new_db_path: &str) -> Result<()> {
+        self.close();
+
+        // Backup if the backup_path does not exist.
+        let backup = !path_exists(BACKUP_PATH);
+
+        // Backup the old db
+        if backup {
+            rename(&self.path, BACKUP_PATH)?;
+        }
+
+        // Restore the new db.
+        match rename(new_db_path, &self.path) {
+            Ok(_) => {
+                // Clean up the backup db.
+                if backup {
+                    remove_dir_all(BACKUP_PATH)?;
+                }
+            }
+            Err(e) => {
+                // Restore the backup db.
+                if backup {
+                    rename(BACKUP_PATH, &self.path)?;
+                }
+                return Err(DatabaseError::Internal(e.to_string()));
+            }
+        }
+
+        // Reopen the database.
+        *self = Self::open(&self.path, &self.config)?;
+        Ok(())
+    }
+
+    fn get_db(&self, category: &Option<DataCategory>) -> Result<Db> {
+        let name = match category {
+            Some(category) => map_columns(category.clone()),
+            None => DEFAULT_DB,
+        };
+        self.dbs.get(name).copied().ok_or(DatabaseError::NotFound)
+    }
+
+    // Run the reads in a read transaction, the default if it is closed.
+    fn view<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Default,
+        F: FnOnce(&RoTxn) -> Result<T>,
+    {
+        match self.env {
+            Some(ref env) => f(&env.read_txn()?),
+            None => Ok(T::default()),
+        }
+    }
+
+    // Run the writes in a write transaction, which is committed if they succeed.
+    fn update<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Default,
+        F: FnOnce(&mut RwTxn) -> Result<T>,
+    {
+        match self.env {
+            Some(ref env) => {
+                let mut txn = env.write_txn()?;
+                let result = f(&mut txn)?;
+                txn.commit()?;
+                Ok(result)
+            }
+            None => Ok(T::default()),
+        }
+    }
+
+    fn put(
+        &self,
+        txn: &mut RwTxn,
+        category: &Option<DataCategory>,
+        key: &[u8],
+        value: &[u8],
+    ) -> Result<()> {
+        self.get_db(category)?.put(txn, key, value)?;
+        Ok(())
+    }
+
+    fn delete(&self, txn: &mut RwTxn, category: &Option<DataCategory>, key: &[u8]) -> Result<()> {
+        self.get_db(category)?.delete(txn, key)?;
+        Ok(())
+    }
+
+    // The merge operator of the category, which the operand is valid for.
+    fn merge_operator(
+        &self,
+        category: &Option<DataCategory>,
+        operand: &[u8],
+    ) -> Result<MergeOperator> {
+        let operator = category
+            .as_ref()
+            .and_then(|category| self.config.category(category).merge_operator())
+            .ok_or(DatabaseError::InvalidData)?;
+        operator.check_operand(operand)?;
+        Ok(operator)
+    }
+
+    fn merge_value(
+        &self,
+        txn: &mut RwTxn,
+        operator: MergeOperator,
+        category: &Option<DataCategory>,
+        key: &[u8],
+        operand: &[u8],
+    ) -> Result<()> {
+        let db = self.get_db(category)?;
+        let existing = db.get(txn, key)?.map(|v| v.to_vec());
+        let value = operator.apply(existing.as_deref(), vec![operand])?;
+        db.put(txn, key, &value)?;
+        Ok(())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn clean_db(&self) {
+        if path_exists(&self.path) {
+            remove_dir_all(&self.path).unwrap();
+        }
+    }
+}
+
+impl Database for LmdbDB {
+    fn get(&self, category: Option<DataCategory>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        self.view(|txn| {
+            let value = self.get_db(&category)?.get(txn, key)?;
+            Ok(value.map(|v| v.to_vec()))
+        })
+    }
+
+    fn get_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: &[Vec<u8>],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        self.view(|txn| {
+            let db = self.get_db(&category)?;
+            keys.iter()
+                .map(|key| Ok(db.get(txn, key)?.map(|v| v.to_vec())))
+                .collect()
+        })
+    }
+
+    fn insert(&self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.update(|txn| self.put(txn, &category, &key, &value))
+    }
+
+    fn insert_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        if keys.len() != values.len() {
+            return Err(DatabaseError::InvalidData);
+        }
+
+        self.update(|txn| {
+            for (key, value) in keys.iter().zip(values.iter()) {
+                self.put(txn, &category, key, value)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn contains(&self, category: Option<DataCategory>, key: &[u8]) -> Result<bool> {
+        self.view(|txn| Ok(self.get_db(&category)?.get(txn, key)?.is_some()))
+    }
+
+    fn remove(&self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        self.update(|txn| self.delete(txn, &category, key))
+    }
+
+    fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()> {
+        self.update(|txn| {
+            for key in keys {
+                self.delete(txn, &category, key)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn merge(&self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let operator = self.merge_operator(&category, &operand)?;
+        self.update(|txn| self.merge_value(txn, operator, &category, &key, &operand))
+    }
+
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.update(|txn| {
+            let db = self.get_db(&category)?;
+            if db.get(txn, key)? != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => db.put(txn, key, &value)?,
+                None => {
+                    db.delete(txn, key)?;
+                }
+            }
+            Ok(true)
+        })
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        self.update(|txn| {
+            for op in batch {
+                match op {
+                    BatchOp::Insert {
+                        category,
+                        key,
+                        value,
+                    } => self.put(txn, &category, &key, &value)?,
+                    BatchOp::Remove { category, key } => self.delete(txn, &category, &key)?,
+                    BatchOp::Merge {
+                        category,
+                        key,
+                        operand,
+                    } => {
+                        let operator = self.merge_operator(&category, &operand)?;
+                        self.merge_value(txn, operator, &category, &key, &operand)?
+                    }
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn restore(&mut self, new_db: &str) -> Result<()> {
+        LmdbDB::restore(self, new_db)
+    }
+
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
+        self.env.as_ref()?;
+        let db = self.get_db(&category).ok()?;
+        Some(Box::new(ChunkIter {
+            lmdb: self,
+            db,
+            last: None,
+            chunk: VecDeque::new(),
+            done: false,
+        }))
+    }
+
+    fn close(&mut self) {
+        LmdbDB::close(self)
+    }
+
+    fn flush(&self) -> Result<()> {
+        if let Some(ref env) = self.env {
+            env.force_sync()?;
+        }
+
+        Ok(())
+    }
+}
+
+// Iterate a sub-database in chunks, each read in its own transaction, so that
+// no transaction is held between the calls. The keys written after it starts
+// may be seen.
+struct ChunkIter<'a> {
+    lmdb: &'a LmdbDB,
+    db: Db,
+    // The last key read.
+    last: Option<Vec<u8>>,
+    chunk: VecDeque<Entry>,
+    done: bool,
+}
+
+impl<'a> ChunkIter<'a> {
+    fn read_chunk(&mut self) -> Result<()> {
+        let (db, last) = (self.db, self.last.as_deref());
+        let chunk: VecDeque<Entry> = self.lmdb.view(|txn| {
+            let start = last.map_or(Bound::Unbounded, Bound::Excluded);
+            db.range(txn, &(start, Bound::Unbounded))?
+                .take(ITER_CHUNK)
+                .map(|item| {
+                    let (key, value) = item?;
+                    Ok((key.into(), value.into()))
+                })
+                .collect()
+        })?;
+
+        self.done = chunk.len() < ITER_CHUNK;
+        if let Some((key, _)) = chunk.back() {
+            self.last = Some(key.to_vec());
+        }
+        self.chunk = chunk;
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ChunkIter<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.chunk.is_empty() && !self.done {
+            if let Err(e) = self.read_chunk() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.chunk.pop_front().map(Ok)
+    }
+}
+
+fn path_exists(path: &str) -> bool {
+    metadata(Path::new(path)).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{path_exists, LmdbDB, BACKUP_PATH, ITER_CHUNK};
+    use crate::columns::NUM_COLUMNS;
+    use crate::config::Config;
+    use crate::database::{DataCategory, Database};
+    use crate::error::DatabaseError;
+    use crate::test::{
+        batch_op, compare_and_swap, insert_get_contains_remove, merge, merge_config,
+        merge_malformed, write_batch,
+    };
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_insert_get_contains_remove() {
+        let cfg = Config::with_category_num(NUM_COLUMNS);
+        let db = LmdbDB::open("lmdb_test/insert_get_contains_remove", &cfg).unwrap();
+
+        insert_get_contains_remove(&db, None);
+        insert_get_contains_remove(&db, Some(DataCategory::State));
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_batch_op() {
+        let cfg = Config::with_category_num(NUM_COLUMNS);
+        let db = LmdbDB::open("lmdb_test/batch_op", &cfg).unwrap();
+
+        batch_op(&db, None);
+        batch_op(&db, Some(DataCategory::State));
+        write_batch(&db);
+        match db.insert_batch(None, vec![b"test".to_vec()], vec![]) {
+            Err(DatabaseError::InvalidData) => (), // pass
+            _ => panic!("should return error DatabaseError::InvalidData"),
+        }
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let cfg = Config::with_category_num(NUM_COLUMNS);
+        let db = LmdbDB::open("lmdb_test/compare_and_swap", &cfg).unwrap();
+
+        compare_and_swap(&db, None);
+        compare_and_swap(&db, Some(DataCategory::State));
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_merge() {
+        let db = LmdbDB::open("lmdb_test/merge", &merge_config()).unwrap();
+
+        merge(&db);
+        merge_malformed(&db);
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_category_not_found() {
+        let db = LmdbDB::open_default("lmdb_test/category_not_found").unwrap();
+
+        assert_eq!(
+            db.get(Some(DataCategory::State), b"test"),
+            Err(DatabaseError::NotFound)
+        );
+        assert!(db.iterator(Some(DataCategory::State)).is_none());
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_iterator() {
+        let cfg = Config::with_category_num(NUM_COLUMNS);
+        let db = LmdbDB::open("lmdb_test/iterator", &cfg).unwrap();
+        let category = Some(DataCategory::State);
+        // More than a chunk
+        let keys: Vec<_> = (0..ITER_CHUNK as u32 * 2 + 1)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        db.insert_batch(category.clone(), keys.clone(), keys.clone())
+            .unwrap();
+        db.insert(None, b"test".to_vec(), b"test".to_vec()).unwrap();
+
+        let contents: Vec<_> = db
+            .iterator(category)
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(contents.len(), keys.len());
+        assert!(contents
+            .iter()
+            .zip(keys.iter())
+            .all(|((key, value), expected)| **key == **expected && **value == **expected));
+
+        let contents: Vec<_> = db.iterator(None).into_iter().flatten().collect();
+        assert_eq!(contents, vec![Ok((b"test"[..].into(), b"test"[..].into()))]);
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_close() {
+        let mut db = LmdbDB::open_default("lmdb_test/close").unwrap();
+        let data = b"test".to_vec();
+        db.insert(None, data.clone(), data.clone()).unwrap();
+        assert_eq!(db.contains(None, &data), Ok(true));
+        db.flush().unwrap();
+        db.close();
+        // Can not query
+        assert_eq!(db.contains(None, &data), Ok(false));
+        assert!(db.iterator(None).is_none());
+
+        // Can open it again and query
+        let db = LmdbDB::open_default("lmdb_test/close").unwrap();
+        assert_eq!(db.contains(None, &data), Ok(true));
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_restore() {
+        if path_exists(BACKUP_PATH) {
+            remove_dir_all(BACKUP_PATH).unwrap();
+        }
+        let mut db = LmdbDB::open_default("lmdb_test/restore").unwrap();
+        let new_path = "lmdb_test/restore_new_db";
+        let mut new_db = LmdbDB::open_default(new_path).unwrap();
+        let data = b"test".to_vec();
+        new_db.insert(None, data.clone(), data.clone()).unwrap();
+        new_db.close();
+
+        assert_eq!(db.contains(None, &data), Ok(false));
+        assert_eq!(db.restore(new_path), Ok(()));
+        assert_eq!(db.contains(None, &data), Ok(true));
+        assert!(!path_exists(new_path));
+        db.clean_db();
+    }
+}
@@ -1,19 +1,280 @@
-use std::collections::HashMap;
-use std::sync::{Arc, RwLock};
-
-use crate::database::{DataCategory, Database, Result};
-use crate::error::DatabaseError;
-use rocksdb::DBIterator;
+use std::collections::{HashMap as StdHashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+use std::sync::{Arc, RwLock, Weak};
+
+use crate::batch::{Batch, BatchOp};
+use crate::config::Config;
+use crate::database::{DBIterator, DataCategory, Database, Result};
+use crate::error::{map_rwlock_err, DatabaseError};
+use crate::export::{read_entries, write_entries};
+use crate::merge::MergeOperator;
+use crate::transaction::Transaction;
+use im::HashMap;
 
 // For tests
 pub struct MemoryDB {
-    storage: Arc<RwLock<HashMap<Vec<u8>, Vec<u8>>>>,
+    storage: Arc<RwLock<Storage>>,
+    // The merge operators of the categories.
+    merge_operators: StdHashMap<DataCategory, MergeOperator>,
+    // The storage of the database it is forked from.
+    parent: Option<Weak<RwLock<Storage>>>,
+}
+
+#[derive(Default)]
+struct Storage {
+    // A persistent map, so cloning it for a fork is cheap.
+    data: HashMap<Vec<u8>, Vec<u8>>,
+    // The keys changed since it was forked, `None` if it is not a fork.
+    changed: Option<HashSet<Vec<u8>>>,
+    // The version of the last write of each key, for the transaction conflicts.
+    // Only kept while any transaction is open.
+    versions: HashMap<Vec<u8>, u64>,
+    // The version of the last write.
+    version: u64,
+    // Number of the open transactions.
+    transactions: usize,
+}
+
+impl Storage {
+    fn get(&self, key: &[u8]) -> Option<&Vec<u8>> {
+        self.data.get(key)
+    }
+
+    fn contains_key(&self, key: &[u8]) -> bool {
+        self.data.contains_key(key)
+    }
+
+    fn insert(&mut self, key: Vec<u8>, value: Vec<u8>) {
+        self.touch(&key);
+        self.data.insert(key, value);
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        self.touch(key);
+        self.data.remove(key);
+    }
+
+    // The version of the last write of the key, 0 if it is never written.
+    fn key_version(&self, key: &[u8]) -> u64 {
+        self.versions.get(key).copied().unwrap_or_default()
+    }
+
+    // Record a write of the key.
+    fn touch(&mut self, key: &[u8]) {
+        if let Some(ref mut changed) = self.changed {
+            changed.insert(key.to_vec());
+        }
+        self.version += 1;
+        if self.transactions > 0 {
+            self.versions.insert(key.to_vec(), self.version);
+        }
+    }
 }
 
 impl MemoryDB {
     pub fn open() -> Self {
-        MemoryDB {
-            storage: Arc::new(RwLock::new(HashMap::new())),
+        Self::default()
+    }
+
+    /// Open with the merge operators of the categories in the config, the other
+    /// options are not used. Return `DatabaseError::InvalidData` if a category
+    /// has a time-to-live, which it does not apply.
+    pub fn with_config(config: &Config) -> Result<Self> {
+        config.check_no_ttl()?;
+        let merge_operators = config
+            .categories
+            .iter()
+            .filter_map(|(category, c)| Some((category.clone(), c.merge_operator()?)))
+            .collect();
+
+        Ok(MemoryDB {
+            merge_operators,
+            ..Default::default()
+        })
+    }
+
+    /// Fork a new database sharing the data of this one.
+    ///
+    /// The data is copied on write, so forking costs O(1). Changes to the fork
+    /// are not visible in this database until `merge_into` is called.
+    pub fn fork(&self) -> Result<Self> {
+        let storage = self.storage.read().map_err(|_| map_rwlock_err())?;
+
+        Ok(MemoryDB {
+            storage: Arc::new(RwLock::new(Storage {
+                data: storage.data.clone(),
+                changed: Some(HashSet::new()),
+                ..Default::default()
+            })),
+            merge_operators: self.merge_operators.clone(),
+            parent: Some(Arc::downgrade(&self.storage)),
+        })
+    }
+
+    /// Apply the changes of this fork to the parent atomically.
+    ///
+    /// Keys changed in the parent since the fork are overwritten. Return
+    /// `DatabaseError::InvalidData` if it is not forked from the parent.
+    pub fn merge_into(self, parent: &MemoryDB) -> Result<()> {
+        let forked_from_parent = self
+            .parent
+            .as_ref()
+            .is_some_and(|p| Weak::as_ptr(p) == Arc::as_ptr(&parent.storage));
+        if !forked_from_parent {
+            return Err(DatabaseError::InvalidData);
+        }
+
+        let fork = self.storage.read().map_err(|_| map_rwlock_err())?;
+        let changed = fork.changed.as_ref().ok_or(DatabaseError::InvalidData)?;
+
+        let mut storage = parent.storage.write().map_err(|_| map_rwlock_err())?;
+        for key in changed {
+            match fork.get(key) {
+                Some(value) => storage.insert(key.to_vec(), value.to_vec()),
+                None => storage.remove(key),
+            }
+        }
+        Ok(())
+    }
+
+    /// Begin a transaction.
+    ///
+    /// The versions of the keys read are checked at commit, so that it fails
+    /// if another writer changed any of them.
+    pub fn transaction(&self) -> MemoryTransaction<'_> {
+        let open = match self.storage.write() {
+            Ok(mut storage) => {
+                storage.transactions += 1;
+                true
+            }
+            Err(_) => false,
+        };
+        MemoryTransaction {
+            db: self,
+            open,
+            reads: StdHashMap::new(),
+            writes: StdHashMap::new(),
+        }
+    }
+
+    /// Save all the data to a dump file at the given path.
+    pub fn save_to(&self, path: &str) -> Result<()> {
+        let storage = self.storage.read().map_err(|_| map_rwlock_err())?;
+        // Sort the entries so that the same data always gives the same file.
+        let mut entries: Vec<_> = storage.data.iter().collect();
+        entries.sort();
+        let entries = entries.into_iter().map(|(key, value)| {
+            let (category, key) = split_key(key);
+            (category, key.to_vec(), value.to_vec())
+        });
+
+        let file = File::create(path)?;
+        write_entries(BufWriter::new(file), entries)
+    }
+
+    /// Load a memory database from a dump file at the given path.
+    pub fn load_from(path: &str) -> Result<Self> {
+        let file = File::open(path)?;
+        let data = read_entries(BufReader::new(file))?
+            .into_iter()
+            .map(|(category, key, value)| (gen_key(&category, key), value))
+            .collect();
+
+        Ok(MemoryDB {
+            storage: Arc::new(RwLock::new(Storage {
+                data,
+                ..Default::default()
+            })),
+            ..Default::default()
+        })
+    }
+
+    // The merge operator of the category, which the operand is valid for.
+    fn merge_operator(
+        &self,
+        category: &Option<DataCategory>,
+        operand: &[u8],
+    ) -> Result<MergeOperator> {
+        let operator = category
+            .as_ref()
+            .and_then(|category| self.merge_operators.get(category))
+            .copied()
+            .ok_or(DatabaseError::InvalidData)?;
+        operator.check_operand(operand)?;
+        Ok(operator)
+    }
+}
+
+/// A transaction of `MemoryDB`.
+pub struct MemoryTransaction<'a> {
+    db: &'a MemoryDB,
+    // Whether it is counted in the open transactions.
+    open: bool,
+    // The version of each key read.
+    reads: StdHashMap<Vec<u8>, u64>,
+    // The pending writes, `None` for a removed key.
+    writes: StdHashMap<Vec<u8>, Option<Vec<u8>>>,
+}
+
+impl<'a> Transaction for MemoryTransaction<'a> {
+    fn get_for_update(
+        &mut self,
+        category: Option<DataCategory>,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let key = gen_key(&category, key.to_vec());
+        if let Some(value) = self.writes.get(&key) {
+            return Ok(value.clone());
+        }
+
+        let storage = self.db.storage.read().map_err(|_| map_rwlock_err())?;
+        let version = storage.key_version(&key);
+        let value = storage.get(&key).cloned();
+        self.reads.entry(key).or_insert(version);
+        Ok(value)
+    }
+
+    fn put(&mut self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.writes.insert(gen_key(&category, key), Some(value));
+        Ok(())
+    }
+
+    fn delete(&mut self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        self.writes.insert(gen_key(&category, key.to_vec()), None);
+        Ok(())
+    }
+
+    fn commit(mut self) -> Result<()> {
+        let mut storage = self.db.storage.write().map_err(|_| map_rwlock_err())?;
+        if self
+            .reads
+            .iter()
+            .any(|(key, version)| storage.key_version(key) != *version)
+        {
+            return Err(DatabaseError::Conflict);
+        }
+
+        for (key, value) in std::mem::take(&mut self.writes) {
+            match value {
+                Some(value) => storage.insert(key, value),
+                None => storage.remove(&key),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Drop for MemoryTransaction<'a> {
+    fn drop(&mut self) {
+        if !self.open {
+            return;
+        }
+        if let Ok(mut storage) = self.db.storage.write() {
+            storage.transactions -= 1;
+            if storage.transactions == 0 {
+                storage.versions.clear();
+            }
         }
     }
 }
@@ -21,7 +282,9 @@ impl MemoryDB {
 impl Default for MemoryDB {
     fn default() -> Self {
         MemoryDB {
-            storage: Arc::new(RwLock::new(HashMap::new())),
+            storage: Arc::new(RwLock::new(Storage::default())),
+            merge_operators: StdHashMap::new(),
+            parent: None,
         }
     }
 }
@@ -116,20 +379,104 @@ impl Database for MemoryDB {
         Ok(())
     }
 
-    fn restore(&mut self, _new_db: &str) -> Result<()> {
-        unimplemented!()
+    fn merge(&self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let operator = self.merge_operator(&category, &operand)?;
+        let key = gen_key(&category, key);
+
+        let mut storage = self.storage.write().map_err(|_| map_rwlock_err())?;
+        let value = operator.apply(storage.get(&key).map(|v| v.as_slice()), vec![&operand[..]])?;
+        storage.insert(key, value);
+        Ok(())
+    }
+
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let key = gen_key(&category, key.to_vec());
+
+        let mut storage = self.storage.write().map_err(|_| map_rwlock_err())?;
+        if storage.get(&key).map(|v| v.as_slice()) != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => storage.insert(key, value),
+            None => storage.remove(&key),
+        }
+        Ok(true)
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        let storage = Arc::clone(&self.storage);
+
+        let mut storage = storage.write().map_err(|_| map_rwlock_err())?;
+        // The new values, written once every merge has succeeded.
+        let mut changes: StdHashMap<Vec<u8>, Option<Vec<u8>>> = StdHashMap::new();
+        for op in batch {
+            match op {
+                BatchOp::Insert {
+                    category,
+                    key,
+                    value,
+                } => changes.insert(gen_key(&category, key), Some(value)),
+                BatchOp::Remove { category, key } => changes.insert(gen_key(&category, key), None),
+                BatchOp::Merge {
+                    category,
+                    key,
+                    operand,
+                } => {
+                    let operator = self.merge_operator(&category, &operand)?;
+                    let key = gen_key(&category, key);
+                    let existing = match changes.get(&key) {
+                        Some(value) => value.as_deref(),
+                        None => storage.get(&key).map(|v| v.as_slice()),
+                    };
+                    let value = operator.apply(existing, vec![&operand[..]])?;
+                    changes.insert(key, Some(value))
+                }
+            };
+        }
+        for (key, value) in changes {
+            match value {
+                Some(value) => storage.insert(key, value),
+                None => storage.remove(&key),
+            }
+        }
+        Ok(())
     }
 
-    fn iterator(&self, _category: Option<DataCategory>) -> Option<DBIterator> {
-        unimplemented!()
+    fn restore(&mut self, _new_db: &str) -> Result<()> {
+        Err(DatabaseError::Internal(
+            "restore is not supported by the memory database".to_string(),
+        ))
     }
 
-    fn close(&mut self) {
-        unimplemented!();
+    /// Iterate a snapshot of the category, in the order of the keys.
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
+        let data = match self.storage.read() {
+            Ok(storage) => storage.data.clone(),
+            Err(_) => return Some(Box::new(std::iter::once(Err(map_rwlock_err())))),
+        };
+        let mut entries: Vec<_> = data
+            .into_iter()
+            .filter_map(|(key, value)| {
+                let (key_category, key) = split_key(&key);
+                (key_category == category)
+                    .then(|| (key.to_vec().into_boxed_slice(), value.into_boxed_slice()))
+            })
+            .collect();
+        entries.sort();
+        Some(Box::new(entries.into_iter().map(Ok)))
     }
 
+    // Nothing to close or flush, the data is dropped with the database.
+    fn close(&mut self) {}
+
     fn flush(&self) -> Result<()> {
-        unimplemented!();
+        Ok(())
     }
 }
 
@@ -143,25 +490,50 @@ fn gen_key(category: &Option<DataCategory>, key: Vec<u8>) -> Vec<u8> {
             DataCategory::Trace => [b"trace-".to_vec(), key].concat(),
             DataCategory::AccountBloom => [b"account-bloom-".to_vec(), key].concat(),
             DataCategory::Other => [b"other-".to_vec(), key].concat(),
+            DataCategory::Journal => [b"journal-".to_vec(), key].concat(),
+            DataCategory::Undo => [b"undo-".to_vec(), key].concat(),
         },
         None => key,
     }
 }
 
-fn gen_keys(category: &Option<DataCategory>, keys: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
-    keys.into_iter().map(|key| gen_key(category, key)).collect()
+// The inverse of `gen_key`.
+fn split_key(key: &[u8]) -> (Option<DataCategory>, &[u8]) {
+    let prefixes: [(&[u8], DataCategory); 9] = [
+        (b"state-", DataCategory::State),
+        (b"headers-", DataCategory::Headers),
+        (b"bodies-", DataCategory::Bodies),
+        (b"extra-", DataCategory::Extra),
+        (b"trace-", DataCategory::Trace),
+        (b"account-bloom-", DataCategory::AccountBloom),
+        (b"other-", DataCategory::Other),
+        (b"journal-", DataCategory::Journal),
+        (b"undo-", DataCategory::Undo),
+    ];
+    for (prefix, category) in prefixes.iter() {
+        if let Some(key) = key.strip_prefix(*prefix) {
+            return (Some(category.clone()), key);
+        }
+    }
+    (None, key)
 }
 
-fn map_rwlock_err() -> DatabaseError {
-    DatabaseError::Internal("rwlock error".to_string())
+fn gen_keys(category: &Option<DataCategory>, keys: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    keys.into_iter().map(|key| gen_key(category, key)).collect()
 }
 
 #[cfg(test)]
 mod tests {
     use super::MemoryDB;
+    use crate::config::{CategoryConfig, Config};
     use crate::database::{DataCategory, Database};
     use crate::error::DatabaseError;
-    use crate::test::{batch_op, insert_get_contains_remove};
+    use crate::test::{
+        batch_op, compare_and_swap, insert_get_contains_remove, merge, merge_config,
+        merge_malformed, transaction_conflict, write_batch,
+    };
+    use crate::ttl::Ttl;
+    use std::env::temp_dir;
 
     #[test]
     fn test_insert_get_contains_remove_with_category() {
@@ -192,6 +564,29 @@ mod tests {
         batch_op(&db, Some(DataCategory::State));
     }
 
+    #[test]
+    fn test_write_batch() {
+        let db = MemoryDB::open();
+
+        write_batch(&db);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let db = MemoryDB::open();
+
+        compare_and_swap(&db, None);
+        compare_and_swap(&db, Some(DataCategory::State));
+    }
+
+    #[test]
+    fn test_merge() {
+        let db = MemoryDB::with_config(&merge_config()).unwrap();
+
+        merge(&db);
+        merge_malformed(&db);
+    }
+
     #[test]
     fn test_insert_batch_error() {
         let db = MemoryDB::open();
@@ -203,4 +598,122 @@ mod tests {
             _ => panic!("should return error DatabaseError::InvalidData"),
         }
     }
+
+    #[test]
+    fn test_close_flush_restore() {
+        let mut db = MemoryDB::open();
+        db.insert(None, b"test".to_vec(), b"value".to_vec())
+            .unwrap();
+
+        assert_eq!(db.flush(), Ok(()));
+        db.close();
+        assert_eq!(db.get(None, b"test"), Ok(Some(b"value".to_vec())));
+        match db.restore("memorydb_test/restore") {
+            Err(DatabaseError::Internal(_)) => (), // pass
+            _ => panic!("should return error DatabaseError::Internal"),
+        }
+    }
+
+    #[test]
+    fn test_save_load() {
+        let db = MemoryDB::open();
+        let path = temp_dir().join("memorydb_test_save_load");
+        let path = path.to_str().unwrap();
+
+        let data = b"test".to_vec();
+        db.insert(None, data.clone(), data.clone()).unwrap();
+        db.insert(Some(DataCategory::State), data.clone(), b"state".to_vec())
+            .unwrap();
+        db.save_to(path).unwrap();
+
+        let loaded = MemoryDB::load_from(path).unwrap();
+        assert_eq!(loaded.get(None, &data), Ok(Some(data.clone())));
+        assert_eq!(
+            loaded.get(Some(DataCategory::State), &data),
+            Ok(Some(b"state".to_vec()))
+        );
+        assert_eq!(loaded.get(Some(DataCategory::Extra), &data), Ok(None));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_fork_merge() {
+        let db = MemoryDB::open();
+        let data1 = b"test1".to_vec();
+        let data2 = b"test2".to_vec();
+        db.insert(None, data1.clone(), data1.clone()).unwrap();
+
+        let fork = db.fork().unwrap();
+        fork.remove(None, &data1).unwrap();
+        fork.insert(None, data2.clone(), data2.clone()).unwrap();
+
+        // The parent is not changed by the fork.
+        assert_eq!(db.contains(None, &data1), Ok(true));
+        assert_eq!(db.contains(None, &data2), Ok(false));
+
+        // A fork of the fork.
+        let nested = fork.fork().unwrap();
+        nested
+            .insert(Some(DataCategory::State), data1.clone(), data2.clone())
+            .unwrap();
+        nested.merge_into(&fork).unwrap();
+
+        fork.merge_into(&db).unwrap();
+        assert_eq!(db.contains(None, &data1), Ok(false));
+        assert_eq!(db.get(None, &data2), Ok(Some(data2.clone())));
+        assert_eq!(
+            db.get(Some(DataCategory::State), &data1),
+            Ok(Some(data2.clone()))
+        );
+    }
+
+    #[test]
+    fn test_ttl_error() {
+        let mut config = Config::default();
+        config.categories.insert(
+            DataCategory::Trace,
+            CategoryConfig {
+                ttl: Some(Ttl::seconds(1)),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            MemoryDB::with_config(&config).err(),
+            Some(DatabaseError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_merge_not_fork_error() {
+        let db = MemoryDB::open();
+        let other = MemoryDB::open();
+
+        assert_eq!(other.merge_into(&db), Err(DatabaseError::InvalidData));
+
+        // A fork of another database, or of the fork itself.
+        let fork = MemoryDB::open().fork().unwrap();
+        assert_eq!(fork.merge_into(&db), Err(DatabaseError::InvalidData));
+        let fork = db.fork().unwrap();
+        let nested = fork.fork().unwrap();
+        assert_eq!(nested.merge_into(&db), Err(DatabaseError::InvalidData));
+        let nested = fork.fork().unwrap();
+        assert_eq!(nested.merge_into(&fork), Ok(()));
+    }
+
+    #[test]
+    fn test_transaction_conflict() {
+        let db = MemoryDB::open();
+
+        transaction_conflict(|| db.transaction());
+
+        // The versions are only kept while any transaction is open.
+        assert!(db.storage.read().unwrap().versions.is_empty());
+        db.insert(None, b"test".to_vec(), vec![]).unwrap();
+        assert!(db.storage.read().unwrap().versions.is_empty());
+        let txn = db.transaction();
+        db.insert(None, b"test".to_vec(), vec![]).unwrap();
+        assert_eq!(db.storage.read().unwrap().versions.len(), 1);
+        drop(txn);
+        assert!(db.storage.read().unwrap().versions.is_empty());
+    }
 }
@@ -0,0 +1,169 @@
+//! Built-in merge operators, to update a value without reading it first.
+
+use crate::database::Result;
+use crate::error::DatabaseError;
+#[cfg(feature = "rocksdb")]
+use rocksdb::MergeOperands;
+
+// The signature of a RocksDB merge function.
+#[cfg(feature = "rocksdb")]
+pub(crate) type MergeFn = fn(&[u8], Option<&[u8]>, &MergeOperands) -> Option<Vec<u8>>;
+
+/// The merge operator of a category, see `Database::merge`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MergeOperator {
+    /// Add the operands to the value, as little-endian u64 wrapping on overflow.
+    /// A stored value which is not 8 bytes fails the merge, except in RocksDB
+    /// where it is taken as 0.
+    U64Add,
+    /// Append the operands to the value.
+    Append,
+}
+
+impl MergeOperator {
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            MergeOperator::U64Add => "u64_add",
+            MergeOperator::Append => "append",
+        }
+    }
+
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn merge_fn(self) -> MergeFn {
+        match self {
+            MergeOperator::U64Add => u64_add,
+            MergeOperator::Append => append,
+        }
+    }
+
+    /// Check the operand is valid for the operator.
+    pub(crate) fn check_operand(self, operand: &[u8]) -> Result<()> {
+        match self {
+            MergeOperator::U64Add if operand.len() != 8 => Err(DatabaseError::InvalidData),
+            _ => Ok(()),
+        }
+    }
+
+    /// Apply the operands in order to the existing value, failing with
+    /// `InvalidData` on a malformed value or operand.
+    pub(crate) fn apply<'a, I>(self, existing: Option<&[u8]>, operands: I) -> Result<Vec<u8>>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        match self {
+            MergeOperator::U64Add => {
+                let mut sum = match existing {
+                    Some(value) => decode_u64(value).ok_or(DatabaseError::InvalidData)?,
+                    None => 0,
+                };
+                for operand in operands {
+                    sum = sum.wrapping_add(decode_u64(operand).ok_or(DatabaseError::InvalidData)?);
+                }
+                Ok(sum.to_le_bytes().to_vec())
+            }
+            MergeOperator::Append => Ok(append_all(existing, operands)),
+        }
+    }
+
+    /// Apply the operands like `apply`, but never fail, so that a malformed value
+    /// does not stop the reads and the compaction of RocksDB: it is taken as the
+    /// empty value, and the invalid operands, which are rejected by
+    /// `check_operand`, are skipped.
+    #[cfg(feature = "rocksdb")]
+    fn apply_lenient<'a, I>(self, existing: Option<&[u8]>, operands: I) -> Vec<u8>
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        match self {
+            MergeOperator::U64Add => {
+                let mut sum = existing.and_then(decode_u64).unwrap_or_default();
+                for operand in operands.into_iter().filter_map(decode_u64) {
+                    sum = sum.wrapping_add(operand);
+                }
+                sum.to_le_bytes().to_vec()
+            }
+            MergeOperator::Append => append_all(existing, operands),
+        }
+    }
+}
+
+fn append_all<'a, I>(existing: Option<&[u8]>, operands: I) -> Vec<u8>
+where
+    I: IntoIterator<Item = &'a [u8]>,
+{
+    let mut value = existing.unwrap_or_default().to_vec();
+    for operand in operands {
+        value.extend_from_slice(operand);
+    }
+    value
+}
+
+fn decode_u64(bytes: &[u8]) -> Option<u64> {
+    let mut num = [0u8; 8];
+    if bytes.len() != num.len() {
+        return None;
+    }
+    num.copy_from_slice(bytes);
+    Some(u64::from_le_bytes(num))
+}
+
+#[cfg(feature = "rocksdb")]
+fn u64_add(_key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
+    Some(MergeOperator::U64Add.apply_lenient(existing, operands))
+}
+
+#[cfg(feature = "rocksdb")]
+fn append(_key: &[u8], existing: Option<&[u8]>, operands: &MergeOperands) -> Option<Vec<u8>> {
+    Some(MergeOperator::Append.apply_lenient(existing, operands))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MergeOperator;
+    use crate::error::DatabaseError;
+
+    #[test]
+    fn test_u64_add() {
+        let one = 1u64.to_le_bytes();
+        let max = u64::MAX.to_le_bytes();
+
+        assert_eq!(
+            MergeOperator::U64Add.apply(None, vec![&one[..], &one[..]]),
+            Ok(2u64.to_le_bytes().to_vec())
+        );
+        assert_eq!(
+            MergeOperator::U64Add.apply(Some(&max), vec![&one[..]]),
+            Ok(0u64.to_le_bytes().to_vec())
+        );
+        // Malformed value and operand
+        assert_eq!(
+            MergeOperator::U64Add.apply(Some(b"test"), vec![&one[..]]),
+            Err(DatabaseError::InvalidData)
+        );
+        assert_eq!(
+            MergeOperator::U64Add.apply(Some(&one), vec![&b"test"[..]]),
+            Err(DatabaseError::InvalidData)
+        );
+        assert!(MergeOperator::U64Add.check_operand(b"test").is_err());
+    }
+
+    #[cfg(feature = "rocksdb")]
+    #[test]
+    fn test_u64_add_lenient() {
+        let one = 1u64.to_le_bytes();
+        assert_eq!(
+            MergeOperator::U64Add.apply_lenient(Some(b"test"), vec![&one[..], &b"test"[..]]),
+            1u64.to_le_bytes().to_vec()
+        );
+    }
+
+    #[test]
+    fn test_append() {
+        assert_eq!(
+            MergeOperator::Append.apply(Some(b"a"), vec![&b"b"[..], &b"c"[..]]),
+            Ok(b"abc".to_vec())
+        );
+        assert_eq!(MergeOperator::Append.apply(None, vec![]), Ok(Vec::new()));
+    }
+}
@@ -0,0 +1,472 @@
+use std::collections::HashMap;
+use std::sync::RwLock;
+
+use crate::batch::{Batch, BatchOp};
+use crate::config::Config;
+use crate::database::{DBIterator, DataCategory, Database, Result};
+use crate::error::{map_rwlock_err, DatabaseError};
+use crate::merge::MergeOperator;
+
+// A pending change of a key.
+#[derive(Clone)]
+enum Change {
+    // Set to the value, `None` to remove it
+    Value(Option<Vec<u8>>),
+    // Merge the operands into the value of the backing database
+    Merge(Vec<Vec<u8>>),
+}
+
+// The pending changes of each category.
+type Overlay = HashMap<Option<DataCategory>, HashMap<Vec<u8>, Change>>;
+
+/// A write overlay in memory over a backing database.
+///
+/// Inserts, removes and merges are buffered in the overlay, and reads look at the
+/// overlay first, then the backing database. `commit` writes the changes to the
+/// backing database as one atomic batch and `revert` discards them.
+pub struct OverlayDB<D: Database> {
+    backing: D,
+    overlay: RwLock<Overlay>,
+    // The merge operators of the categories.
+    merge_operators: HashMap<DataCategory, MergeOperator>,
+}
+
+impl<D: Database> OverlayDB<D> {
+    pub fn new(backing: D) -> Self {
+        OverlayDB {
+            backing,
+            overlay: RwLock::new(HashMap::new()),
+            merge_operators: HashMap::new(),
+        }
+    }
+
+    /// Create with the merge operators of the categories in the config, which
+    /// should be the ones of the backing database.
+    pub fn with_config(backing: D, config: &Config) -> Self {
+        let merge_operators = config
+            .categories
+            .iter()
+            .filter_map(|(category, c)| Some((category.clone(), c.merge_operator()?)))
+            .collect();
+
+        OverlayDB {
+            merge_operators,
+            ..Self::new(backing)
+        }
+    }
+
+    /// The backing database.
+    pub fn backing(&self) -> &D {
+        &self.backing
+    }
+
+    /// Take the backing database, discarding the pending changes.
+    pub fn into_inner(self) -> D {
+        self.backing
+    }
+
+    /// Write the pending changes to the backing database as one atomic batch.
+    ///
+    /// The buffered merge operands are written as merges, so they apply to the
+    /// value of the backing database at the commit.
+    pub fn commit(&self) -> Result<()> {
+        let mut overlay = self.overlay.write().map_err(|_| map_rwlock_err())?;
+
+        let mut batch = Batch::new();
+        for (category, changes) in overlay.iter() {
+            for (key, change) in changes.iter() {
+                match change {
+                    Change::Value(Some(value)) => {
+                        batch.insert(category.clone(), key.to_vec(), value.to_vec())
+                    }
+                    Change::Value(None) => batch.remove(category.clone(), key.to_vec()),
+                    Change::Merge(operands) => {
+                        for operand in operands {
+                            batch.merge(category.clone(), key.to_vec(), operand.to_vec());
+                        }
+                    }
+                }
+            }
+        }
+        self.backing.write(batch)?;
+
+        overlay.clear();
+        Ok(())
+    }
+
+    /// Discard the pending changes.
+    pub fn revert(&self) -> Result<()> {
+        let mut overlay = self.overlay.write().map_err(|_| map_rwlock_err())?;
+        overlay.clear();
+        Ok(())
+    }
+
+    // Set the key in the overlay, `None` to remove it.
+    fn set(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Option<Vec<u8>>>,
+    ) -> Result<()> {
+        let mut overlay = self.overlay.write().map_err(|_| map_rwlock_err())?;
+        let changes = overlay.entry(category).or_default();
+        for (key, value) in keys.into_iter().zip(values) {
+            changes.insert(key, Change::Value(value));
+        }
+        Ok(())
+    }
+
+    fn merge_operator(&self, category: &Option<DataCategory>) -> Result<MergeOperator> {
+        category
+            .as_ref()
+            .and_then(|category| self.merge_operators.get(category))
+            .copied()
+            .ok_or(DatabaseError::InvalidData)
+    }
+
+    // The pending change of the key after merging the operand into it.
+    fn merged(
+        &self,
+        category: &Option<DataCategory>,
+        change: Option<&Change>,
+        operand: Vec<u8>,
+    ) -> Result<Change> {
+        let operator = self.merge_operator(category)?;
+        operator.check_operand(&operand)?;
+        match change {
+            Some(Change::Value(value)) => Ok(Change::Value(Some(
+                operator.apply(value.as_deref(), vec![&operand[..]])?,
+            ))),
+            Some(Change::Merge(operands)) => {
+                let mut operands = operands.clone();
+                operands.push(operand);
+                Ok(Change::Merge(operands))
+            }
+            None => Ok(Change::Merge(vec![operand])),
+        }
+    }
+
+    // The value of the key after the change, merging into the backing value.
+    fn resolve(
+        &self,
+        category: &Option<DataCategory>,
+        key: &[u8],
+        change: &Change,
+    ) -> Result<Option<Vec<u8>>> {
+        match change {
+            Change::Value(value) => Ok(value.clone()),
+            Change::Merge(operands) => {
+                let existing = self.backing.get(category.clone(), key)?;
+                self.merge_operator(category)?
+                    .apply(
+                        existing.as_deref(),
+                        operands.iter().map(|operand| operand.as_slice()),
+                    )
+                    .map(Some)
+            }
+        }
+    }
+}
+
+impl<D: Database> Database for OverlayDB<D> {
+    fn get(&self, category: Option<DataCategory>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let change = {
+            let overlay = self.overlay.read().map_err(|_| map_rwlock_err())?;
+            overlay
+                .get(&category)
+                .and_then(|changes| changes.get(key))
+                .cloned()
+        };
+
+        match change {
+            Some(change) => self.resolve(&category, key, &change),
+            None => self.backing.get(category, key),
+        }
+    }
+
+    fn get_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: &[Vec<u8>],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        let mut values = Vec::with_capacity(keys.len());
+        // The keys to read from the backing database, with their positions and
+        // the operands to merge.
+        let mut missing = Vec::new();
+        {
+            let overlay = self.overlay.read().map_err(|_| map_rwlock_err())?;
+            let changes = overlay.get(&category);
+            for (i, key) in keys.iter().enumerate() {
+                values.push(None);
+                match changes.and_then(|changes| changes.get(key)) {
+                    Some(Change::Value(value)) => values[i] = value.clone(),
+                    Some(Change::Merge(operands)) => {
+                        missing.push((i, key.to_vec(), Some(operands.clone())))
+                    }
+                    None => missing.push((i, key.to_vec(), None)),
+                }
+            }
+        }
+
+        if !missing.is_empty() {
+            let keys: Vec<_> = missing.iter().map(|(_, key, _)| key.clone()).collect();
+            let backing_values = self.backing.get_batch(category.clone(), &keys)?;
+            for ((i, _, operands), value) in missing.into_iter().zip(backing_values) {
+                values[i] = match operands {
+                    Some(operands) => Some(self.merge_operator(&category)?.apply(
+                        value.as_deref(),
+                        operands.iter().map(|operand| operand.as_slice()),
+                    )?),
+                    None => value,
+                };
+            }
+        }
+
+        Ok(values)
+    }
+
+    fn insert(&self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.set(category, vec![key], vec![Some(value)])
+    }
+
+    fn insert_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        if keys.len() != values.len() {
+            return Err(DatabaseError::InvalidData);
+        }
+
+        self.set(category, keys, values.into_iter().map(Some).collect())
+    }
+
+    fn contains(&self, category: Option<DataCategory>, key: &[u8]) -> Result<bool> {
+        {
+            let overlay = self.overlay.read().map_err(|_| map_rwlock_err())?;
+            match overlay.get(&category).and_then(|changes| changes.get(key)) {
+                Some(Change::Value(value)) => return Ok(value.is_some()),
+                // A merge always gives a value.
+                Some(Change::Merge(_)) => return Ok(true),
+                None => (),
+            }
+        }
+
+        self.backing.contains(category, key)
+    }
+
+    fn remove(&self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        self.set(category, vec![key.to_vec()], vec![None])
+    }
+
+    fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()> {
+        self.set(category, keys.to_vec(), vec![None; keys.len()])
+    }
+
+    /// Buffer the operand, with the merge operators of `with_config`.
+    fn merge(&self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let mut overlay = self.overlay.write().map_err(|_| map_rwlock_err())?;
+        let changes = overlay.entry(category.clone()).or_default();
+        let change = self.merged(&category, changes.get(&key), operand)?;
+        changes.insert(key, change);
+        Ok(())
+    }
+
+    /// Atomic with the other conditional writes through the overlay, the backing
+    /// database is only read.
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let mut overlay = self.overlay.write().map_err(|_| map_rwlock_err())?;
+        let changes = overlay.entry(category.clone()).or_default();
+        let current = match changes.get(key) {
+            Some(change) => self.resolve(&category, key, change)?,
+            None => self.backing.get(category, key)?,
+        };
+        if current.as_deref() != expected {
+            return Ok(false);
+        }
+        changes.insert(key.to_vec(), Change::Value(new));
+        Ok(true)
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        let mut overlay = self.overlay.write().map_err(|_| map_rwlock_err())?;
+        // The changes of the batch, buffered once every merge has succeeded.
+        let mut staged: HashMap<(Option<DataCategory>, Vec<u8>), Change> = HashMap::new();
+        for op in batch {
+            let (key, change) = match op {
+                BatchOp::Insert {
+                    category,
+                    key,
+                    value,
+                } => ((category, key), Change::Value(Some(value))),
+                BatchOp::Remove { category, key } => ((category, key), Change::Value(None)),
+                BatchOp::Merge {
+                    category,
+                    key,
+                    operand,
+                } => {
+                    let change = staged
+                        .get(&(category.clone(), key.clone()))
+                        .or_else(|| overlay.get(&category).and_then(|changes| changes.get(&key)));
+                    let change = self.merged(&category, change, operand)?;
+                    ((category, key), change)
+                }
+            };
+            staged.insert(key, change);
+        }
+        for ((category, key), change) in staged {
+            overlay.entry(category).or_default().insert(key, change);
+        }
+        Ok(())
+    }
+
+    /// Discard the pending changes and restore the backing database.
+    fn restore(&mut self, new_db: &str) -> Result<()> {
+        self.revert()?;
+        self.backing.restore(new_db)
+    }
+
+    /// Iterate the backing database, the pending changes are not included.
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
+        self.backing.iterator(category)
+    }
+
+    /// Discard the pending changes and close the backing database.
+    fn close(&mut self) {
+        if let Ok(mut overlay) = self.overlay.write() {
+            overlay.clear();
+        }
+        self.backing.close()
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.backing.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::OverlayDB;
+    use crate::database::{DataCategory, Database};
+    use crate::error::DatabaseError;
+    use crate::memorydb::MemoryDB;
+    use crate::test::{
+        batch_op, compare_and_swap, insert_get_contains_remove, merge, merge_config,
+        merge_malformed, write_batch,
+    };
+
+    #[test]
+    fn test_insert_get_contains_remove() {
+        let db = OverlayDB::new(MemoryDB::open());
+
+        insert_get_contains_remove(&db, None);
+        insert_get_contains_remove(&db, Some(DataCategory::State));
+    }
+
+    #[test]
+    fn test_batch_op() {
+        let db = OverlayDB::new(MemoryDB::open());
+
+        batch_op(&db, None);
+        batch_op(&db, Some(DataCategory::State));
+        write_batch(&db);
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let backing = MemoryDB::open();
+        let data = b"test".to_vec();
+        backing.insert(None, data.clone(), data.clone()).unwrap();
+        let db = OverlayDB::new(backing);
+
+        compare_and_swap(&db, Some(DataCategory::State));
+        // Read from the backing database.
+        assert_eq!(db.insert_if_absent(None, data.clone(), vec![]), Ok(false));
+        assert_eq!(
+            db.compare_and_swap(None, &data, Some(&data), None),
+            Ok(true)
+        );
+        assert_eq!(db.backing().contains(None, &data), Ok(true));
+    }
+
+    #[test]
+    fn test_commit_revert() {
+        let backing = MemoryDB::open();
+        let data1 = b"test1".to_vec();
+        let data2 = b"test2".to_vec();
+        backing.insert(None, data1.clone(), data1.clone()).unwrap();
+        let db = OverlayDB::new(backing);
+
+        // Read through the overlay.
+        db.remove(None, &data1).unwrap();
+        db.insert(Some(DataCategory::State), data2.clone(), data2.clone())
+            .unwrap();
+        assert_eq!(
+            db.get_batch(None, &[data1.clone(), data2.clone()]),
+            Ok(vec![None, None])
+        );
+        assert_eq!(db.backing().contains(None, &data1), Ok(true));
+
+        // Revert
+        db.revert().unwrap();
+        assert_eq!(db.get(None, &data1), Ok(Some(data1.clone())));
+        assert_eq!(db.contains(Some(DataCategory::State), &data2), Ok(false));
+
+        // Commit
+        db.remove(None, &data1).unwrap();
+        db.insert(Some(DataCategory::State), data2.clone(), data2.clone())
+            .unwrap();
+        db.commit().unwrap();
+        let backing = db.into_inner();
+        assert_eq!(backing.contains(None, &data1), Ok(false));
+        assert_eq!(
+            backing.get(Some(DataCategory::State), &data2),
+            Ok(Some(data2))
+        );
+    }
+
+    #[test]
+    fn test_merge() {
+        let config = merge_config();
+        let db = OverlayDB::with_config(MemoryDB::with_config(&config).unwrap(), &config);
+        merge(&db);
+        merge_malformed(&db);
+
+        // Merge into the backing value at the commit.
+        let backing = MemoryDB::with_config(&config).unwrap();
+        let counter = Some(DataCategory::State);
+        let data = b"test".to_vec();
+        backing
+            .insert(counter.clone(), data.clone(), 1u64.to_le_bytes().to_vec())
+            .unwrap();
+        let db = OverlayDB::with_config(backing, &config);
+        db.merge(counter.clone(), data.clone(), 2u64.to_le_bytes().to_vec())
+            .unwrap();
+        assert_eq!(
+            db.get_batch(counter.clone(), std::slice::from_ref(&data)),
+            Ok(vec![Some(3u64.to_le_bytes().to_vec())])
+        );
+        db.backing()
+            .merge(counter.clone(), data.clone(), 4u64.to_le_bytes().to_vec())
+            .unwrap();
+        db.commit().unwrap();
+        assert_eq!(
+            db.backing().get(counter, &data),
+            Ok(Some(7u64.to_le_bytes().to_vec()))
+        );
+
+        // No merge operators
+        let db = OverlayDB::new(MemoryDB::with_config(&config).unwrap());
+        assert_eq!(
+            db.merge(Some(DataCategory::State), data, vec![0; 8]),
+            Err(DatabaseError::InvalidData)
+        );
+    }
+}
@@ -0,0 +1,510 @@
+//! The binary protocol between `Server` and `RemoteDB`.
+//!
+//! A request is `[op][fields]`, replied with `[OK][reply][fields]` or
+//! `[ERR][error]`. The bytes are length-prefixed and the categories tagged as in
+//! the dump format. An iteration is replied with a stream of `[ITEM][key][value]`,
+//! ended by `[END]` or `[ERR][error]`.
+//!
+//! A request, reply or item is at most `MAX_FRAME_LEN` bytes, and a longer length
+//! is rejected before it is read.
+
+use std::io::{Read, Take, Write};
+
+use crate::batch::{Batch, BatchOp};
+use crate::database::{DataCategory, Result};
+use crate::error::DatabaseError;
+use crate::export::{category_to_tag, read_u8, tag_to_category, write_bytes};
+
+pub(crate) const OP_GET: u8 = 1;
+const OP_GET_BATCH: u8 = 2;
+const OP_INSERT_BATCH: u8 = 3;
+const OP_REMOVE_BATCH: u8 = 4;
+const OP_MERGE: u8 = 5;
+const OP_COMPARE_AND_SWAP: u8 = 6;
+const OP_WRITE: u8 = 7;
+const OP_ITERATE: u8 = 8;
+const OP_FLUSH: u8 = 9;
+
+const REPLY_DONE: u8 = 0;
+const REPLY_VALUE: u8 = 1;
+const REPLY_VALUES: u8 = 2;
+const REPLY_SWAPPED: u8 = 3;
+
+const BATCH_INSERT: u8 = 0;
+const BATCH_REMOVE: u8 = 1;
+const BATCH_MERGE: u8 = 2;
+
+/// The most bytes read for a request, a reply or an item.
+pub(crate) const MAX_FRAME_LEN: u64 = 256 * 1024 * 1024;
+
+pub(crate) const OK: u8 = 0;
+pub(crate) const ERR: u8 = 1;
+pub(crate) const ITEM: u8 = 2;
+pub(crate) const END: u8 = 3;
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Request {
+    Get {
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+    },
+    GetBatch {
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+    },
+    InsertBatch {
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    },
+    RemoveBatch {
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+    },
+    Merge {
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+        operand: Vec<u8>,
+    },
+    CompareAndSwap {
+        category: Option<DataCategory>,
+        key: Vec<u8>,
+        expected: Option<Vec<u8>>,
+        new: Option<Vec<u8>>,
+    },
+    Write(Batch),
+    Iterate {
+        category: Option<DataCategory>,
+    },
+    Flush,
+}
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum Reply {
+    Done,
+    Value(Option<Vec<u8>>),
+    Values(Vec<Option<Vec<u8>>>),
+    Swapped(bool),
+}
+
+impl Request {
+    pub(crate) fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Request::Get { category, key } => {
+                writer.write_all(&[OP_GET, category_to_tag(category)])?;
+                write_bytes(writer, key)
+            }
+            Request::GetBatch { category, keys } => {
+                writer.write_all(&[OP_GET_BATCH, category_to_tag(category)])?;
+                write_list(writer, keys)
+            }
+            Request::InsertBatch {
+                category,
+                keys,
+                values,
+            } => {
+                writer.write_all(&[OP_INSERT_BATCH, category_to_tag(category)])?;
+                write_list(writer, keys)?;
+                write_list(writer, values)
+            }
+            Request::RemoveBatch { category, keys } => {
+                writer.write_all(&[OP_REMOVE_BATCH, category_to_tag(category)])?;
+                write_list(writer, keys)
+            }
+            Request::Merge {
+                category,
+                key,
+                operand,
+            } => {
+                writer.write_all(&[OP_MERGE, category_to_tag(category)])?;
+                write_bytes(writer, key)?;
+                write_bytes(writer, operand)
+            }
+            Request::CompareAndSwap {
+                category,
+                key,
+                expected,
+                new,
+            } => {
+                writer.write_all(&[OP_COMPARE_AND_SWAP, category_to_tag(category)])?;
+                write_bytes(writer, key)?;
+                write_option(writer, expected)?;
+                write_option(writer, new)
+            }
+            Request::Write(batch) => {
+                writer.write_all(&[OP_WRITE])?;
+                write_len(writer, batch.len())?;
+                for op in batch.ops() {
+                    match op {
+                        BatchOp::Insert {
+                            category,
+                            key,
+                            value,
+                        } => {
+                            writer.write_all(&[BATCH_INSERT, category_to_tag(category)])?;
+                            write_bytes(writer, key)?;
+                            write_bytes(writer, value)?;
+                        }
+                        BatchOp::Remove { category, key } => {
+                            writer.write_all(&[BATCH_REMOVE, category_to_tag(category)])?;
+                            write_bytes(writer, key)?;
+                        }
+                        BatchOp::Merge {
+                            category,
+                            key,
+                            operand,
+                        } => {
+                            writer.write_all(&[BATCH_MERGE, category_to_tag(category)])?;
+                            write_bytes(writer, key)?;
+                            write_bytes(writer, operand)?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Request::Iterate { category } => {
+                writer.write_all(&[OP_ITERATE, category_to_tag(category)])?;
+                Ok(())
+            }
+            Request::Flush => {
+                writer.write_all(&[OP_FLUSH])?;
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn read_from<R: Read>(reader: &mut R) -> Result<Self> {
+        let reader = &mut reader.by_ref().take(MAX_FRAME_LEN);
+        let request = match read_u8(reader)? {
+            OP_GET => Request::Get {
+                category: read_category(reader)?,
+                key: read_bytes(reader)?,
+            },
+            OP_GET_BATCH => Request::GetBatch {
+                category: read_category(reader)?,
+                keys: read_list(reader)?,
+            },
+            OP_INSERT_BATCH => Request::InsertBatch {
+                category: read_category(reader)?,
+                keys: read_list(reader)?,
+                values: read_list(reader)?,
+            },
+            OP_REMOVE_BATCH => Request::RemoveBatch {
+                category: read_category(reader)?,
+                keys: read_list(reader)?,
+            },
+            OP_MERGE => Request::Merge {
+                category: read_category(reader)?,
+                key: read_bytes(reader)?,
+                operand: read_bytes(reader)?,
+            },
+            OP_COMPARE_AND_SWAP => Request::CompareAndSwap {
+                category: read_category(reader)?,
+                key: read_bytes(reader)?,
+                expected: read_option(reader)?,
+                new: read_option(reader)?,
+            },
+            OP_WRITE => {
+                let mut batch = Batch::new();
+                for _ in 0..read_len(reader)? {
+                    match read_u8(reader)? {
+                        BATCH_INSERT => batch.insert(
+                            read_category(reader)?,
+                            read_bytes(reader)?,
+                            read_bytes(reader)?,
+                        ),
+                        BATCH_REMOVE => batch.remove(read_category(reader)?, read_bytes(reader)?),
+                        BATCH_MERGE => batch.merge(
+                            read_category(reader)?,
+                            read_bytes(reader)?,
+                            read_bytes(reader)?,
+                        ),
+                        _ => return Err(DatabaseError::InvalidData),
+                    }
+                }
+                Request::Write(batch)
+            }
+            OP_ITERATE => Request::Iterate {
+                category: read_category(reader)?,
+            },
+            OP_FLUSH => Request::Flush,
+            _ => return Err(DatabaseError::InvalidData),
+        };
+        Ok(request)
+    }
+}
+
+impl Reply {
+    fn write_to<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Reply::Done => {
+                writer.write_all(&[REPLY_DONE])?;
+                Ok(())
+            }
+            Reply::Value(value) => {
+                writer.write_all(&[REPLY_VALUE])?;
+                write_option(writer, value)
+            }
+            Reply::Values(values) => {
+                writer.write_all(&[REPLY_VALUES])?;
+                write_len(writer, values.len())?;
+                for value in values {
+                    write_option(writer, value)?;
+                }
+                Ok(())
+            }
+            Reply::Swapped(swapped) => {
+                writer.write_all(&[REPLY_SWAPPED, u8::from(*swapped)])?;
+                Ok(())
+            }
+        }
+    }
+
+    fn read_from<R: Read>(reader: &mut Take<R>) -> Result<Self> {
+        let reply = match read_u8(reader)? {
+            REPLY_DONE => Reply::Done,
+            REPLY_VALUE => Reply::Value(read_option(reader)?),
+            REPLY_VALUES => {
+                let len = read_len(reader)?;
+                let mut values = Vec::with_capacity(len.min(1024));
+                for _ in 0..len {
+                    values.push(read_option(reader)?);
+                }
+                Reply::Values(values)
+            }
+            REPLY_SWAPPED => Reply::Swapped(read_u8(reader)? != 0),
+            _ => return Err(DatabaseError::InvalidData),
+        };
+        Ok(reply)
+    }
+}
+
+/// Write the result of a request.
+pub(crate) fn write_result<W: Write>(writer: &mut W, result: &Result<Reply>) -> Result<()> {
+    match result {
+        Ok(reply) => {
+            writer.write_all(&[OK])?;
+            reply.write_to(writer)
+        }
+        Err(err) => {
+            writer.write_all(&[ERR])?;
+            write_error(writer, err)
+        }
+    }
+}
+
+/// Read the result of a request. The outer error is of the connection, and the
+/// inner one of the database.
+pub(crate) fn read_result<R: Read>(reader: &mut R) -> Result<Result<Reply>> {
+    let reader = &mut reader.by_ref().take(MAX_FRAME_LEN);
+    match read_u8(reader)? {
+        OK => Ok(Ok(Reply::read_from(reader)?)),
+        ERR => Ok(Err(read_error(reader)?)),
+        _ => Err(DatabaseError::InvalidData),
+    }
+}
+
+pub(crate) fn write_error<W: Write>(writer: &mut W, err: &DatabaseError) -> Result<()> {
+    match err {
+        DatabaseError::NotFound => writer.write_all(&[0])?,
+        DatabaseError::InvalidData => writer.write_all(&[1])?,
+        DatabaseError::Conflict => writer.write_all(&[2])?,
+        DatabaseError::ReadOnly => writer.write_all(&[3])?,
+        DatabaseError::Internal(message) => {
+            writer.write_all(&[4])?;
+            write_bytes(writer, message.as_bytes())?;
+        }
+    }
+    Ok(())
+}
+
+pub(crate) fn read_error<R: Read>(reader: &mut R) -> Result<DatabaseError> {
+    let reader = &mut reader.by_ref().take(MAX_FRAME_LEN);
+    let err = match read_u8(reader)? {
+        0 => DatabaseError::NotFound,
+        1 => DatabaseError::InvalidData,
+        2 => DatabaseError::Conflict,
+        3 => DatabaseError::ReadOnly,
+        4 => DatabaseError::Internal(String::from_utf8_lossy(&read_bytes(reader)?).into_owned()),
+        _ => return Err(DatabaseError::InvalidData),
+    };
+    Ok(err)
+}
+
+/// Read an item of an iteration, `None` at the end.
+pub(crate) fn read_item<R: Read>(reader: &mut R) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+    match read_u8(reader)? {
+        ITEM => {
+            let reader = &mut reader.by_ref().take(MAX_FRAME_LEN);
+            Ok(Some((read_bytes(reader)?, read_bytes(reader)?)))
+        }
+        END => Ok(None),
+        ERR => Err(read_error(reader)?),
+        _ => Err(DatabaseError::InvalidData),
+    }
+}
+
+// Reject a length beyond the rest of the frame, before reading it.
+fn read_bytes<R: Read>(reader: &mut Take<R>) -> Result<Vec<u8>> {
+    let len = read_len(reader)? as u64;
+    if len > reader.limit() {
+        return Err(DatabaseError::InvalidData);
+    }
+    let mut bytes = Vec::new();
+    if reader.take(len).read_to_end(&mut bytes)? as u64 != len {
+        return Err(DatabaseError::InvalidData);
+    }
+    Ok(bytes)
+}
+
+fn read_category<R: Read>(reader: &mut R) -> Result<Option<DataCategory>> {
+    tag_to_category(read_u8(reader)?)
+}
+
+fn write_len<W: Write>(writer: &mut W, len: usize) -> Result<()> {
+    let len = u32::try_from(len).map_err(|_| DatabaseError::InvalidData)?;
+    writer.write_all(&len.to_le_bytes())?;
+    Ok(())
+}
+
+fn read_len<R: Read>(reader: &mut R) -> Result<usize> {
+    let mut len = [0u8; 4];
+    reader.read_exact(&mut len)?;
+    Ok(u32::from_le_bytes(len) as usize)
+}
+
+fn write_list<W: Write>(writer: &mut W, list: &[Vec<u8>]) -> Result<()> {
+    write_len(writer, list.len())?;
+    for bytes in list {
+        write_bytes(writer, bytes)?;
+    }
+    Ok(())
+}
+
+fn read_list<R: Read>(reader: &mut Take<R>) -> Result<Vec<Vec<u8>>> {
+    let len = read_len(reader)?;
+    // Do not trust the length to preallocate.
+    let mut list = Vec::with_capacity(len.min(1024));
+    for _ in 0..len {
+        list.push(read_bytes(reader)?);
+    }
+    Ok(list)
+}
+
+fn write_option<W: Write>(writer: &mut W, bytes: &Option<Vec<u8>>) -> Result<()> {
+    match bytes {
+        Some(bytes) => {
+            writer.write_all(&[1])?;
+            write_bytes(writer, bytes)
+        }
+        None => {
+            writer.write_all(&[0])?;
+            Ok(())
+        }
+    }
+}
+
+fn read_option<R: Read>(reader: &mut Take<R>) -> Result<Option<Vec<u8>>> {
+    match read_u8(reader)? {
+        0 => Ok(None),
+        1 => Ok(Some(read_bytes(reader)?)),
+        _ => Err(DatabaseError::InvalidData),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read_result, write_result, Reply, Request, MAX_FRAME_LEN, OP_GET, OP_GET_BATCH};
+    use crate::batch::Batch;
+    use crate::database::DataCategory;
+    use crate::error::DatabaseError;
+    use std::io::{self, Read};
+
+    #[test]
+    fn test_request_roundtrip() {
+        let mut batch = Batch::new();
+        batch.insert(
+            Some(DataCategory::State),
+            b"key".to_vec(),
+            b"value".to_vec(),
+        );
+        batch.remove(None, b"key".to_vec());
+        batch.merge(Some(DataCategory::Other), b"key".to_vec(), vec![1]);
+        let requests = vec![
+            Request::GetBatch {
+                category: Some(DataCategory::Headers),
+                keys: vec![b"key1".to_vec(), Vec::new()],
+            },
+            Request::CompareAndSwap {
+                category: None,
+                key: b"key".to_vec(),
+                expected: None,
+                new: Some(b"new".to_vec()),
+            },
+            Request::Write(batch),
+            Request::Flush,
+        ];
+
+        let mut buf = Vec::new();
+        for request in &requests {
+            request.write_to(&mut buf).unwrap();
+        }
+        let mut reader = buf.as_slice();
+        for request in requests {
+            assert_eq!(Request::read_from(&mut reader), Ok(request));
+        }
+        assert!(reader.is_empty());
+
+        assert_eq!(
+            Request::read_from(&mut [0xffu8].as_slice()),
+            Err(DatabaseError::InvalidData)
+        );
+    }
+
+    #[test]
+    fn test_result_roundtrip() {
+        let results = vec![
+            Ok(Reply::Values(vec![Some(b"value".to_vec()), None])),
+            Ok(Reply::Swapped(true)),
+            Err(DatabaseError::Conflict),
+            Err(DatabaseError::Internal("test".to_owned())),
+        ];
+
+        let mut buf = Vec::new();
+        for result in &results {
+            write_result(&mut buf, result).unwrap();
+        }
+        let mut reader = buf.as_slice();
+        for result in results {
+            assert_eq!(read_result(&mut reader), Ok(result));
+        }
+    }
+
+    #[test]
+    fn test_oversized_length() {
+        // The length is rejected before the bytes are read, which never end.
+        let mut head = vec![OP_GET, 0];
+        head.extend_from_slice(&u32::MAX.to_le_bytes());
+        let mut reader = head.as_slice().chain(io::repeat(0));
+        assert_eq!(
+            Request::read_from(&mut reader),
+            Err(DatabaseError::InvalidData)
+        );
+
+        // The keys of a request are limited together.
+        let key_len = MAX_FRAME_LEN as u32 / 2;
+        let len = key_len.to_le_bytes();
+        let mut head = vec![OP_GET_BATCH, 0];
+        head.extend_from_slice(&3u32.to_le_bytes());
+        head.extend_from_slice(&key_len.to_le_bytes());
+        let mut reader = head
+            .as_slice()
+            .chain(io::repeat(0).take(u64::from(key_len)))
+            .chain(len.as_slice())
+            .chain(io::repeat(0));
+        assert_eq!(
+            Request::read_from(&mut reader),
+            Err(DatabaseError::InvalidData)
+        );
+    }
+}
@@ -0,0 +1,331 @@
+use std::collections::HashSet;
+
+use crate::batch::Batch;
+use crate::config::Config;
+use crate::database::{DataCategory, Database, Result};
+use crate::error::DatabaseError;
+
+// The key of the earliest height still in the journal.
+const EARLIEST_KEY: &[u8] = b"prune-earliest-height";
+// The prefix of the journal keys, followed by the height.
+const JOURNAL_PREFIX: &[u8] = b"prune-";
+
+/// Configuration of the state pruning.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PruningConfig {
+    /// Number of recent blocks whose state is kept, `None` for the archive mode
+    /// which keeps everything.
+    pub history: Option<u64>,
+}
+
+/// Prune the state trie nodes by block height.
+///
+/// For each block, the nodes inserted and dereferenced in `DataCategory::State`
+/// are recorded in `DataCategory::Journal`. When a block falls out of the history
+/// window, the nodes it dereferenced are deleted, unless a newer block inserted
+/// them again. If the state is ref-counted, each of them drops one reference instead.
+pub struct StatePruner {
+    config: PruningConfig,
+    // Whether `DataCategory::State` is ref-counted, see `CategoryConfig::ref_counted`.
+    ref_counted: bool,
+}
+
+// The state changes of a block.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct JournalRecord {
+    inserted: Vec<Vec<u8>>,
+    dereferenced: Vec<Vec<u8>>,
+}
+
+impl StatePruner {
+    /// Create with the config of the database, to know whether the state is
+    /// ref-counted.
+    pub fn new(config: PruningConfig, db_config: &Config) -> Self {
+        StatePruner {
+            config,
+            ref_counted: db_config.category(&DataCategory::State).ref_counted,
+        }
+    }
+
+    /// Journal the state changes of the block at the height, and prune the
+    /// blocks out of the history window.
+    ///
+    /// The changes are added to the batch, so that they are written atomically
+    /// with the block. The blocks must be journaled in order of height.
+    pub fn journal<D: Database>(
+        &self,
+        db: &D,
+        batch: &mut Batch,
+        height: u64,
+        inserted: Vec<Vec<u8>>,
+        dereferenced: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        let history = match self.config.history {
+            Some(history) => history,
+            None => return Ok(()),
+        };
+
+        let earliest = match db.get(Some(DataCategory::Journal), EARLIEST_KEY)? {
+            Some(earliest) => decode_height(&earliest)?,
+            None => height,
+        };
+        let record = JournalRecord {
+            inserted,
+            dereferenced,
+        };
+
+        // The blocks from `prune_end` are kept.
+        let prune_end = (height + 1).saturating_sub(history).max(earliest);
+        if prune_end > earliest {
+            self.prune(db, batch, earliest, prune_end, height, &record)?;
+        }
+
+        if prune_end <= height {
+            batch.insert(
+                Some(DataCategory::Journal),
+                journal_key(height),
+                record.encode(),
+            );
+        } else {
+            // No history is kept, so the dereferenced nodes are deleted right away.
+            for key in record.dereferenced {
+                if self.ref_counted || !record.inserted.contains(&key) {
+                    batch.remove(Some(DataCategory::State), key);
+                }
+            }
+        }
+        batch.insert(
+            Some(DataCategory::Journal),
+            EARLIEST_KEY.to_vec(),
+            prune_end.to_be_bytes().to_vec(),
+        );
+
+        Ok(())
+    }
+
+    // Prune the blocks from `earliest` to `prune_end`. A dereferenced node is
+    // deleted unless a later block, up to the current one, inserted it again.
+    fn prune<D: Database>(
+        &self,
+        db: &D,
+        batch: &mut Batch,
+        earliest: u64,
+        prune_end: u64,
+        height: u64,
+        current: &JournalRecord,
+    ) -> Result<()> {
+        // The nodes inserted after the block being pruned, read once.
+        let mut inserted: HashSet<Vec<u8>> = HashSet::new();
+        if !self.ref_counted {
+            inserted.extend(current.inserted.iter().cloned());
+            for h in prune_end..height {
+                if let Some(record) = self.read_record(db, h)? {
+                    inserted.extend(record.inserted);
+                }
+            }
+        }
+
+        for expired in (earliest..prune_end).rev() {
+            if let Some(record) = self.read_record(db, expired)? {
+                for key in record.dereferenced {
+                    if !inserted.contains(&key) {
+                        batch.remove(Some(DataCategory::State), key);
+                    }
+                }
+                if !self.ref_counted {
+                    inserted.extend(record.inserted);
+                }
+            }
+            batch.remove(Some(DataCategory::Journal), journal_key(expired));
+        }
+        Ok(())
+    }
+
+    fn read_record<D: Database>(&self, db: &D, height: u64) -> Result<Option<JournalRecord>> {
+        db.get(Some(DataCategory::Journal), &journal_key(height))?
+            .map(|bytes| JournalRecord::decode(&bytes))
+            .transpose()
+    }
+}
+
+impl JournalRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for keys in [&self.inserted, &self.dereferenced] {
+            bytes.extend_from_slice(&(keys.len() as u32).to_be_bytes());
+            for key in keys.iter() {
+                bytes.extend_from_slice(&(key.len() as u32).to_be_bytes());
+                bytes.extend_from_slice(key);
+            }
+        }
+        bytes
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let inserted = decode_keys(&mut bytes)?;
+        let dereferenced = decode_keys(&mut bytes)?;
+        if !bytes.is_empty() {
+            return Err(DatabaseError::InvalidData);
+        }
+
+        Ok(JournalRecord {
+            inserted,
+            dereferenced,
+        })
+    }
+}
+
+fn decode_keys(bytes: &mut &[u8]) -> Result<Vec<Vec<u8>>> {
+    let num = decode_u32(bytes)?;
+    let mut keys = Vec::new();
+    for _ in 0..num {
+        let len = decode_u32(bytes)? as usize;
+        if bytes.len() < len {
+            return Err(DatabaseError::InvalidData);
+        }
+        let (key, rest) = bytes.split_at(len);
+        keys.push(key.to_vec());
+        *bytes = rest;
+    }
+    Ok(keys)
+}
+
+fn decode_u32(bytes: &mut &[u8]) -> Result<u32> {
+    if bytes.len() < 4 {
+        return Err(DatabaseError::InvalidData);
+    }
+    let (num, rest) = bytes.split_at(4);
+    *bytes = rest;
+    Ok(u32::from_be_bytes([num[0], num[1], num[2], num[3]]))
+}
+
+fn decode_height(bytes: &[u8]) -> Result<u64> {
+    let mut height = [0u8; 8];
+    if bytes.len() != height.len() {
+        return Err(DatabaseError::InvalidData);
+    }
+    height.copy_from_slice(bytes);
+    Ok(u64::from_be_bytes(height))
+}
+
+fn journal_key(height: u64) -> Vec<u8> {
+    [JOURNAL_PREFIX, &height.to_be_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{journal_key, JournalRecord, PruningConfig, StatePruner};
+    use crate::batch::Batch;
+    use crate::config::{CategoryConfig, Config};
+    use crate::database::{DataCategory, Database};
+    use crate::memorydb::MemoryDB;
+
+    // Insert the nodes and journal the block.
+    fn commit(
+        db: &MemoryDB,
+        pruner: &StatePruner,
+        height: u64,
+        inserted: &[&[u8]],
+        dereferenced: &[&[u8]],
+    ) {
+        let inserted: Vec<Vec<u8>> = inserted.iter().map(|key| key.to_vec()).collect();
+        let dereferenced = dereferenced.iter().map(|key| key.to_vec()).collect();
+
+        let mut batch = Batch::new();
+        for key in inserted.iter() {
+            batch.insert(Some(DataCategory::State), key.clone(), key.clone());
+        }
+        pruner
+            .journal(db, &mut batch, height, inserted, dereferenced)
+            .unwrap();
+        db.write(batch).unwrap();
+    }
+
+    fn contains(db: &MemoryDB, key: &[u8]) -> bool {
+        db.contains(Some(DataCategory::State), key).unwrap()
+    }
+
+    #[test]
+    fn test_prune() {
+        let db = MemoryDB::open();
+        let pruner = StatePruner::new(PruningConfig { history: Some(2) }, &Config::default());
+
+        commit(&db, &pruner, 1, &[b"a", b"b"], &[]);
+        commit(&db, &pruner, 2, &[b"c"], &[b"a", b"b"]);
+        // Inserted again
+        commit(&db, &pruner, 3, &[b"b"], &[]);
+        assert!(contains(&db, b"a"));
+
+        commit(&db, &pruner, 4, &[], &[]);
+        assert!(!contains(&db, b"a"));
+        assert!(contains(&db, b"b"));
+        assert!(contains(&db, b"c"));
+        // Only the history is kept in the journal.
+        let journal = Some(DataCategory::Journal);
+        assert_eq!(db.contains(journal.clone(), &journal_key(2)), Ok(false));
+        assert_eq!(db.contains(journal, &journal_key(3)), Ok(true));
+    }
+
+    #[test]
+    fn test_prune_window() {
+        let db = MemoryDB::open();
+        let pruner = StatePruner::new(PruningConfig { history: Some(3) }, &Config::default());
+
+        commit(&db, &pruner, 1, &[b"a", b"b", b"c"], &[]);
+        commit(&db, &pruner, 2, &[], &[b"a", b"b"]);
+        commit(&db, &pruner, 3, &[b"a"], &[b"c"]);
+        commit(&db, &pruner, 4, &[b"c"], &[b"a"]);
+        assert!(contains(&db, b"a"));
+
+        // Prune the blocks 2 to 4 at once: "a" is inserted again by the block 3
+        // after the block 2, but not after the block 4.
+        let pruner = StatePruner::new(PruningConfig { history: Some(1) }, &Config::default());
+        commit(&db, &pruner, 5, &[], &[]);
+        assert!(!contains(&db, b"a"));
+        assert!(!contains(&db, b"b"));
+        // Inserted again by the block 4 after the block 3
+        assert!(contains(&db, b"c"));
+    }
+
+    #[test]
+    fn test_ref_counted() {
+        let db = MemoryDB::open();
+        let mut config = Config::default();
+        config.categories.insert(
+            DataCategory::State,
+            CategoryConfig {
+                ref_counted: true,
+                ..Default::default()
+            },
+        );
+        let pruner = StatePruner::new(PruningConfig { history: Some(1) }, &config);
+
+        // Each dereference drops one reference, even if inserted again.
+        commit(&db, &pruner, 1, &[b"a"], &[]);
+        commit(&db, &pruner, 2, &[b"a"], &[b"a"]);
+        commit(&db, &pruner, 3, &[], &[]);
+        assert!(!contains(&db, b"a"));
+    }
+
+    #[test]
+    fn test_archive() {
+        let db = MemoryDB::open();
+        let pruner = StatePruner::new(PruningConfig::default(), &Config::default());
+
+        commit(&db, &pruner, 1, &[b"a"], &[]);
+        commit(&db, &pruner, 2, &[], &[b"a"]);
+        commit(&db, &pruner, 100, &[], &[]);
+        assert!(contains(&db, b"a"));
+    }
+
+    #[test]
+    fn test_journal_record() {
+        let record = JournalRecord {
+            inserted: vec![b"a".to_vec(), vec![]],
+            dereferenced: vec![b"b".to_vec()],
+        };
+
+        assert_eq!(JournalRecord::decode(&record.encode()), Ok(record));
+        assert!(JournalRecord::decode(b"test").is_err());
+    }
+}
@@ -0,0 +1,166 @@
+//! Reference-counted values, kept with a RocksDB merge operator.
+//!
+//! Every insert or remove of a ref-counted category is written as a merge
+//! operand carrying a count delta, so it does not need to read the old value.
+//! The merged record is `[format: u8][has value: u8][count: i64 LE][value]`, and
+//! a key whose count drops to zero is removed by the compaction filter. The format
+//! byte tells the records from the plain values written before the category was
+//! ref-counted, which are rejected instead of misread.
+
+use crate::database::Result;
+use crate::error::DatabaseError;
+use rocksdb::{CompactionDecision, MergeOperands};
+
+pub(crate) const MERGE_OPERATOR_NAME: &str = "refcount_merge";
+pub(crate) const COMPACTION_FILTER_NAME: &str = "refcount_filter";
+
+// The first byte of a record.
+const FORMAT: u8 = 0xc1;
+const HEADER_LEN: usize = 10;
+
+// A merge operand or a merged record.
+struct Record<'a> {
+    value: Option<&'a [u8]>,
+    count: i64,
+}
+
+impl<'a> Record<'a> {
+    fn decode(bytes: &'a [u8]) -> Option<Self> {
+        if bytes.len() < HEADER_LEN || bytes[0] != FORMAT {
+            return None;
+        }
+        let mut count = [0u8; 8];
+        count.copy_from_slice(&bytes[2..HEADER_LEN]);
+        let value = match bytes[1] {
+            0 => None,
+            1 => Some(&bytes[HEADER_LEN..]),
+            _ => return None,
+        };
+
+        Some(Record {
+            value,
+            count: i64::from_le_bytes(count),
+        })
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let value = self.value.unwrap_or_default();
+        let mut bytes = Vec::with_capacity(HEADER_LEN + value.len());
+        bytes.push(FORMAT);
+        bytes.push(self.value.is_some() as u8);
+        bytes.extend_from_slice(&self.count.to_le_bytes());
+        bytes.extend_from_slice(value);
+        bytes
+    }
+}
+
+/// The merge operand that adds a reference to the value.
+pub(crate) fn encode_insert(value: &[u8]) -> Vec<u8> {
+    Record {
+        value: Some(value),
+        count: 1,
+    }
+    .encode()
+}
+
+/// The merge operand that drops a reference.
+pub(crate) fn encode_remove() -> Vec<u8> {
+    Record {
+        value: None,
+        count: -1,
+    }
+    .encode()
+}
+
+/// Whether the stored bytes are a record, rather than a plain value.
+pub(crate) fn is_record(bytes: &[u8]) -> bool {
+    Record::decode(bytes).is_some()
+}
+
+/// Get the value of a merged record, `None` if it is no longer referenced.
+pub(crate) fn decode_value(bytes: &[u8]) -> Result<Option<Vec<u8>>> {
+    let record = Record::decode(bytes).ok_or(DatabaseError::InvalidData)?;
+    match record.value {
+        Some(value) if record.count > 0 => Ok(Some(value.to_vec())),
+        _ => Ok(None),
+    }
+}
+
+/// Apply the operands in order to the existing record.
+///
+/// The count never goes below zero, so dropping a reference that is not there
+/// does nothing.
+pub(crate) fn full_merge(
+    _key: &[u8],
+    existing: Option<&[u8]>,
+    operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    let mut merged = match existing {
+        Some(bytes) => Record::decode(bytes)?,
+        None => Record {
+            value: None,
+            count: 0,
+        },
+    };
+
+    for operand in operands.iter() {
+        let operand = Record::decode(operand)?;
+        merged.count = (merged.count + operand.count).max(0);
+        if operand.value.is_some() {
+            merged.value = operand.value;
+        }
+        if merged.count == 0 {
+            merged.value = None;
+        }
+    }
+
+    Some(merged.encode())
+}
+
+/// Operands are not combined without the existing record, since the count is
+/// clamped at zero.
+pub(crate) fn partial_merge(
+    _key: &[u8],
+    _existing: Option<&[u8]>,
+    _operands: &MergeOperands,
+) -> Option<Vec<u8>> {
+    None
+}
+
+/// Remove the keys that are no longer referenced.
+pub(crate) fn compaction_filter(_level: u32, _key: &[u8], value: &[u8]) -> CompactionDecision {
+    match Record::decode(value) {
+        Some(record) if record.count <= 0 => CompactionDecision::Remove,
+        _ => CompactionDecision::Keep,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{compaction_filter, decode_value, encode_insert, encode_remove};
+    use rocksdb::CompactionDecision;
+
+    #[test]
+    fn test_decode_value() {
+        assert_eq!(
+            decode_value(&encode_insert(b"test")),
+            Ok(Some(b"test".to_vec()))
+        );
+        assert_eq!(decode_value(&encode_remove()), Ok(None));
+        assert!(decode_value(b"test").is_err());
+        // A plain value as long as a record
+        assert!(decode_value(&[1; 16]).is_err());
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        match compaction_filter(0, b"key", &encode_remove()) {
+            CompactionDecision::Remove => (), // pass
+            _ => panic!("should remove the unreferenced key"),
+        }
+        match compaction_filter(0, b"key", &encode_insert(b"test")) {
+            CompactionDecision::Keep => (), // pass
+            _ => panic!("should keep the referenced key"),
+        }
+    }
+}
@@ -0,0 +1,444 @@
+//! A database served by `Server` in another process.
+
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::net::{SocketAddr, TcpStream, ToSocketAddrs};
+#[cfg(unix)]
+use std::os::unix::net::UnixStream;
+#[cfg(unix)]
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::batch::Batch;
+use crate::database::{DBIterator, DataCategory, Database, Result};
+use crate::error::{map_mutex_err, DatabaseError};
+use crate::protocol::{read_item, read_result, Reply, Request};
+
+#[derive(Debug, Clone)]
+enum Address {
+    Tcp(Vec<SocketAddr>),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Address {
+    fn connect(&self) -> Result<Connection> {
+        match self {
+            Address::Tcp(addrs) => {
+                let stream = TcpStream::connect(addrs.as_slice())?;
+                stream.set_nodelay(true)?;
+                Ok(Connection::new(
+                    Box::new(stream.try_clone()?),
+                    Box::new(stream),
+                ))
+            }
+            #[cfg(unix)]
+            Address::Unix(path) => {
+                let stream = UnixStream::connect(path)?;
+                Ok(Connection::new(
+                    Box::new(stream.try_clone()?),
+                    Box::new(stream),
+                ))
+            }
+        }
+    }
+}
+
+struct Connection {
+    reader: BufReader<Box<dyn Read + Send>>,
+    writer: BufWriter<Box<dyn Write + Send>>,
+}
+
+impl Connection {
+    fn new(reader: Box<dyn Read + Send>, writer: Box<dyn Write + Send>) -> Self {
+        Connection {
+            reader: BufReader::new(reader),
+            writer: BufWriter::new(writer),
+        }
+    }
+
+    fn send(&mut self, request: &Request) -> Result<()> {
+        request.write_to(&mut self.writer)?;
+        self.writer.flush()?;
+        Ok(())
+    }
+
+    // The outer error is of the connection, and the inner one of the database.
+    fn call(&mut self, request: &Request) -> Result<Result<Reply>> {
+        self.send(request)?;
+        read_result(&mut self.reader)
+    }
+}
+
+/// Connect to a `Server`. A broken connection is dropped, and the next request
+/// connects again.
+pub struct RemoteDB {
+    address: Address,
+    conn: Mutex<Option<Connection>>,
+}
+
+impl RemoteDB {
+    pub fn connect_tcp<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        Self::connect(Address::Tcp(addr.to_socket_addrs()?.collect()))
+    }
+
+    #[cfg(unix)]
+    pub fn connect_unix<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Self::connect(Address::Unix(path.as_ref().to_path_buf()))
+    }
+
+    fn connect(address: Address) -> Result<Self> {
+        let conn = address.connect()?;
+        Ok(RemoteDB {
+            address,
+            conn: Mutex::new(Some(conn)),
+        })
+    }
+
+    fn call(&self, request: Request) -> Result<Reply> {
+        let mut guard = self.conn.lock().map_err(|_| map_mutex_err())?;
+        let conn = match guard.as_mut() {
+            Some(conn) => conn,
+            None => guard.insert(self.address.connect()?),
+        };
+        match conn.call(&request) {
+            Ok(result) => result,
+            Err(err) => {
+                *guard = None;
+                Err(err)
+            }
+        }
+    }
+
+    fn call_done(&self, request: Request) -> Result<()> {
+        match self.call(request)? {
+            Reply::Done => Ok(()),
+            _ => Err(DatabaseError::InvalidData),
+        }
+    }
+}
+
+impl Database for RemoteDB {
+    fn get(&self, category: Option<DataCategory>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.call(Request::Get {
+            category,
+            key: key.to_vec(),
+        })? {
+            Reply::Value(value) => Ok(value),
+            _ => Err(DatabaseError::InvalidData),
+        }
+    }
+
+    fn get_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: &[Vec<u8>],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        match self.call(Request::GetBatch {
+            category,
+            keys: keys.to_vec(),
+        })? {
+            Reply::Values(values) if values.len() == keys.len() => Ok(values),
+            _ => Err(DatabaseError::InvalidData),
+        }
+    }
+
+    fn insert(&self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.insert_batch(category, vec![key], vec![value])
+    }
+
+    fn insert_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        if keys.len() != values.len() {
+            return Err(DatabaseError::InvalidData);
+        }
+        self.call_done(Request::InsertBatch {
+            category,
+            keys,
+            values,
+        })
+    }
+
+    fn contains(&self, category: Option<DataCategory>, key: &[u8]) -> Result<bool> {
+        self.get(category, key).map(|value| value.is_some())
+    }
+
+    fn remove(&self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        self.remove_batch(category, &[key.to_vec()])
+    }
+
+    fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()> {
+        self.call_done(Request::RemoveBatch {
+            category,
+            keys: keys.to_vec(),
+        })
+    }
+
+    fn merge(&self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        self.call_done(Request::Merge {
+            category,
+            key,
+            operand,
+        })
+    }
+
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        match self.call(Request::CompareAndSwap {
+            category,
+            key: key.to_vec(),
+            expected: expected.map(|expected| expected.to_vec()),
+            new,
+        })? {
+            Reply::Swapped(swapped) => Ok(swapped),
+            _ => Err(DatabaseError::InvalidData),
+        }
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        self.call_done(Request::Write(batch))
+    }
+
+    /// The database can only be restored by the server.
+    fn restore(&mut self, _new_db: &str) -> Result<()> {
+        Err(DatabaseError::Internal(
+            "restore is not supported by the remote database".to_string(),
+        ))
+    }
+
+    /// Iterate on a connection of its own, as the entries are streamed.
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
+        let iter = self.address.connect().and_then(|mut conn| {
+            conn.send(&Request::Iterate { category })?;
+            Ok(RemoteIter { conn: Some(conn) })
+        });
+        match iter {
+            Ok(iter) => Some(Box::new(iter)),
+            Err(err) => Some(Box::new(std::iter::once(Err(err)))),
+        }
+    }
+
+    /// Drop the connection, and the next request connects again.
+    fn close(&mut self) {
+        if let Ok(mut conn) = self.conn.lock() {
+            *conn = None;
+        }
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.call_done(Request::Flush)
+    }
+}
+
+type Entry = (Box<[u8]>, Box<[u8]>);
+
+// Read the streamed entries, until the end or an error.
+struct RemoteIter {
+    conn: Option<Connection>,
+}
+
+impl Iterator for RemoteIter {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let conn = self.conn.as_mut()?;
+        match read_item(&mut conn.reader) {
+            Ok(Some((key, value))) => Some(Ok((key.into_boxed_slice(), value.into_boxed_slice()))),
+            Ok(None) => {
+                self.conn = None;
+                None
+            }
+            Err(err) => {
+                self.conn = None;
+                Some(Err(err))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::RemoteDB;
+    use crate::database::{DataCategory, Database};
+    use crate::error::DatabaseError;
+    use crate::memorydb::MemoryDB;
+    use crate::protocol::{read_result, OP_GET};
+    use crate::server::Server;
+    use crate::test::{
+        batch_op, compare_and_swap, insert_get_contains_remove, merge, merge_config, write_batch,
+    };
+    use std::io::{Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+    use std::thread;
+    use std::time::Duration;
+
+    fn serve_tcp<D: Database + 'static>(db: D) -> RemoteDB {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(Arc::new(db));
+        thread::spawn(move || server.serve_tcp(listener));
+        RemoteDB::connect_tcp(addr).unwrap()
+    }
+
+    #[test]
+    fn test_tcp() {
+        let db = serve_tcp(MemoryDB::with_config(&merge_config()).unwrap());
+
+        insert_get_contains_remove(&db, None);
+        insert_get_contains_remove(&db, Some(DataCategory::State));
+        batch_op(&db, Some(DataCategory::Extra));
+        write_batch(&db);
+        compare_and_swap(&db, Some(DataCategory::Headers));
+        merge(&db);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut db = serve_tcp(MemoryDB::open());
+
+        // Merge without a merge operator
+        assert_eq!(
+            db.merge(None, b"test".to_vec(), b"test".to_vec()),
+            Err(DatabaseError::InvalidData)
+        );
+        assert!(db.restore("test").is_err());
+
+        // Reconnect after closed.
+        db.close();
+        insert_get_contains_remove(&db, None);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_unix() {
+        use std::os::unix::net::UnixListener;
+
+        let path = std::env::temp_dir().join(format!("cita_database_{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let server = Server::new(Arc::new(MemoryDB::open()));
+        thread::spawn(move || server.serve_unix(listener));
+
+        let db = RemoteDB::connect_unix(&path).unwrap();
+        insert_get_contains_remove(&db, Some(DataCategory::Trace));
+        batch_op(&db, None);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(feature = "rocksdb")]
+    fn test_iterator() {
+        use crate::config::Config;
+        use crate::rocksdb::RocksDB;
+
+        let path = "rocksdb_test/remote_iterator";
+        let config = Config::with_category_num(crate::columns::NUM_COLUMNS);
+        let db = serve_tcp(RocksDB::open(path, &config).unwrap());
+        let keys: Vec<Vec<u8>> = (0..3000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        db.insert_batch(Some(DataCategory::State), keys.clone(), keys.clone())
+            .unwrap();
+
+        let entries: Vec<Vec<u8>> = db
+            .iterator(Some(DataCategory::State))
+            .unwrap()
+            .map(|item| item.unwrap().0.into_vec())
+            .collect();
+        assert_eq!(entries, keys);
+
+        // Drop the iterator early, the connection of the requests is not affected.
+        let mut iter = db.iterator(Some(DataCategory::State)).unwrap();
+        assert!(iter.next().unwrap().is_ok());
+        drop(iter);
+        assert_eq!(
+            db.get(Some(DataCategory::State), &keys[0]),
+            Ok(Some(keys[0].clone()))
+        );
+
+        drop(db);
+        let _ = std::fs::remove_dir_all(path);
+    }
+
+    #[test]
+    fn test_oversized_request() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(Arc::new(MemoryDB::open()));
+        thread::spawn(move || server.serve_tcp(listener));
+
+        // A get of a key longer than a frame is rejected before the key is read.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut request = vec![OP_GET, 0];
+        request.extend_from_slice(&u32::MAX.to_le_bytes());
+        stream.write_all(&request).unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        assert_eq!(
+            read_result(&mut reply.as_slice()),
+            Ok(Err(DatabaseError::InvalidData))
+        );
+    }
+
+    #[test]
+    fn test_max_connections() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = Server::new(Arc::new(MemoryDB::open())).with_max_connections(1);
+        thread::spawn(move || server.serve_tcp(listener));
+
+        let db = RemoteDB::connect_tcp(addr).unwrap();
+        insert_get_contains_remove(&db, None);
+        // The second connection is closed.
+        let other = RemoteDB::connect_tcp(addr).unwrap();
+        assert!(other.get(None, b"test").is_err());
+
+        // Connect again after the first one is closed.
+        drop(db);
+        let connected = (0..50).any(|_| {
+            thread::sleep(Duration::from_millis(20));
+            other.get(None, b"test").is_ok()
+        });
+        assert!(connected);
+    }
+
+    #[test]
+    fn test_idle_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server =
+            Server::new(Arc::new(MemoryDB::open())).with_timeout(Duration::from_millis(100));
+        thread::spawn(move || server.serve_tcp(listener));
+
+        // An idle connection is closed without a reply.
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut reply = Vec::new();
+        stream.read_to_end(&mut reply).unwrap();
+        assert!(reply.is_empty());
+
+        // The request after the connection is closed fails, and the next one
+        // connects again.
+        let db = RemoteDB::connect_tcp(addr).unwrap();
+        db.insert(None, b"test".to_vec(), b"value".to_vec())
+            .unwrap();
+        thread::sleep(Duration::from_millis(300));
+        assert!(db.get(None, b"test").is_err());
+        assert_eq!(db.get(None, b"test"), Ok(Some(b"value".to_vec())));
+    }
+
+    #[test]
+    fn test_connect_refused() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        drop(listener);
+
+        assert!(RemoteDB::connect_tcp(addr).is_err());
+    }
+}
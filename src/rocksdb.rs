@@ -1,19 +1,30 @@
+use std::collections::hash_map::DefaultHasher;
 use std::default::Default;
+use std::hash::{Hash, Hasher};
 use std::path::Path;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, MutexGuard};
 
+use crate::batch::{Batch, BatchOp};
 use crate::columns::map_columns;
-use crate::config::{Config, BACKGROUND_FLUSHES, WRITE_BUFFER_SIZE};
-use crate::database::{DataCategory, Database, Result};
-use crate::error::DatabaseError;
+use crate::config::{
+    BlobCompression, BlobConfig, Compaction, CompactionStyle, Config, BACKGROUND_FLUSHES,
+    WRITE_BUFFER_SIZE,
+};
+use crate::database::{DBIterator, DataCategory, Database, Result};
+use crate::error::{map_mutex_err, DatabaseError};
+use crate::filter;
+use crate::refcount;
 use rocksdb::{
-    BlockBasedOptions, ColumnFamily, DBCompactionStyle, DBIterator, IteratorMode, Options,
-    ReadOptions, WriteBatch, WriteOptions, DB,
+    BlockBasedOptions, ColumnFamily, ColumnFamilyDescriptor, DBCompactionStyle, DBCompressionType,
+    FifoCompactOptions, IteratorMode, Options, ReadOptions, UniversalCompactOptions, WriteBatch,
+    WriteOptions, DB,
 };
 use std::fs::{metadata, remove_dir_all, rename};
 
 // The backup db path.
 const BACKUP_PATH: &str = "backup_old_db";
+// The number of locks the keys are striped over for the conditional writes.
+const KEY_LOCKS: usize = 64;
 
 // For the future: Add more info about db.
 #[derive(Debug)]
@@ -26,6 +37,10 @@ pub struct RocksDB {
     pub config: Config,
     pub write_opts: WriteOptions,
     path: String,
+    // Opened with `open_read_only` or `open_as_secondary`.
+    read_only: bool,
+    // Serialize the conditional writes of the same key.
+    key_locks: Vec<Mutex<()>>,
 }
 
 // RocksDB guarantees synchronization
@@ -40,51 +55,89 @@ impl RocksDB {
 
     /// Open rocksDB with config.
     pub fn open(path: &str, config: &Config) -> Result<Self> {
-        let mut opts = Options::default();
-        opts.set_write_buffer_size(WRITE_BUFFER_SIZE);
-        opts.set_max_background_jobs(BACKGROUND_FLUSHES);
-
-        opts.create_if_missing(true);
-        // If true, any column families that didn't exist when opening the database will be created.
-        opts.create_missing_column_families(true);
-
-        let block_opts = BlockBasedOptions::default();
-        opts.set_block_based_table_factory(&block_opts);
+        let (opts, write_opts) = open_options(config)?;
+        let db = match config.category_num {
+            Some(_) => DB::open_cf_descriptors(&opts, path, column_descriptors(&opts, config))
+                .map_err(|e| DatabaseError::Internal(e.to_string()))?,
+            None => DB::open(&opts, path).map_err(|e| DatabaseError::Internal(e.to_string()))?,
+        };
+        check_ref_counted(&db, config)?;
 
-        opts.set_max_open_files(config.max_open_files);
-        opts.set_use_fsync(false);
-        opts.set_compaction_style(DBCompactionStyle::Level);
-        opts.set_target_file_size_base(config.compaction.target_file_size_base);
-        if let Some(level_multiplier) = config.compaction.max_bytes_for_level_multiplier {
-            opts.set_max_bytes_for_level_multiplier(level_multiplier);
-        }
-        if let Some(compactions) = config.compaction.max_background_compactions {
-            opts.set_max_background_jobs(compactions);
-        }
+        Ok(Self::with_db(db, config, write_opts, path, false))
+    }
 
-        let mut write_opts = WriteOptions::default();
-        if !config.wal {
-            write_opts.disable_wal(true);
-        }
+    /// Open rocksDB read-only with config, without taking its lock, so it can
+    /// be opened while another process is writing.
+    ///
+    /// The categories must exist, and the writes return `DatabaseError::ReadOnly`.
+    /// It sees the data as of when it is opened.
+    pub fn open_read_only(path: &str, config: &Config) -> Result<Self> {
+        let (opts, write_opts) = open_options(config)?;
+        let db = match config.category_num {
+            Some(_) => DB::open_cf_descriptors_read_only(
+                &opts,
+                path,
+                column_descriptors(&opts, config),
+                false,
+            )?,
+            None => DB::open_for_read_only(&opts, path, false)?,
+        };
+        check_ref_counted(&db, config)?;
 
-        let columns: Vec<_> = (0..config.category_num.unwrap_or(0))
-            .map(|c| format!("col{}", c))
-            .collect();
-        let columns: Vec<&str> = columns.iter().map(|n| n as &str).collect();
-        debug!("[database] Columns: {:?}", columns);
+        Ok(Self::with_db(db, config, write_opts, path, true))
+    }
 
+    /// Open rocksDB as a secondary instance of the primary at the path, with
+    /// config. It keeps its own info log at the secondary path.
+    ///
+    /// It sees the data as of when it is opened, and of the last call to
+    /// `try_catch_up_with_primary`. The writes return `DatabaseError::ReadOnly`.
+    pub fn open_as_secondary(
+        primary_path: &str,
+        secondary_path: &str,
+        config: &Config,
+    ) -> Result<Self> {
+        let (mut opts, write_opts) = open_options(config)?;
+        // A secondary instance keeps all the files open.
+        opts.set_max_open_files(-1);
         let db = match config.category_num {
-            Some(_) => DB::open_cf(&opts, path, columns.iter())
-                .map_err(|e| DatabaseError::Internal(e.to_string()))?,
-            None => DB::open(&opts, path).map_err(|e| DatabaseError::Internal(e.to_string()))?,
+            Some(_) => DB::open_cf_descriptors_as_secondary(
+                &opts,
+                primary_path,
+                secondary_path,
+                column_descriptors(&opts, config),
+            )?,
+            None => DB::open_as_secondary(&opts, primary_path, secondary_path)?,
         };
+        check_ref_counted(&db, config)?;
+
+        Ok(Self::with_db(db, config, write_opts, secondary_path, true))
+    }
+
+    /// Catch up with the latest writes of the primary, if it is opened as a secondary.
+    pub fn try_catch_up_with_primary(&self) -> Result<()> {
+        if let Some(DBInfo { ref db }) = *self.db_info {
+            db.try_catch_up_with_primary()?;
+        }
+
+        Ok(())
+    }
 
-        Ok(RocksDB {
+    fn with_db(
+        db: DB,
+        config: &Config,
+        write_opts: WriteOptions,
+        path: &str,
+        read_only: bool,
+    ) -> Self {
+        RocksDB {
             db_info: Arc::new(Some(DBInfo { db })),
             write_opts,
             config: config.clone(),
             path: path.to_owned(),
-        })
+            read_only,
+            key_locks: (0..KEY_LOCKS).map(|_| Mutex::new(())).collect(),
+        }
     }
 
     pub fn close(&mut self) {
@@ -94,6 +147,7 @@ impl RocksDB {
 
     /// Restore the database from a copy at given path.
     pub fn restore(&mut self, new_db_path: &str) -> Result<()> {
+        self.check_writable()?;
         // Close it first
         // https://github.com/facebook/rocksdb/wiki/Basic-Operations#closing-a-database
         self.close();
@@ -103,21 +157,21 @@ impl RocksDB {
 
         // Backup the old db
         if backup {
-            rename(&self.path, &BACKUP_PATH)?;
+            rename(&self.path, BACKUP_PATH)?;
         }
 
         // Restore the new db.
-        match rename(&new_db_path, &self.path) {
+        match rename(new_db_path, &self.path) {
             Ok(_) => {
                 // Clean up the backup db.
                 if backup {
-                    remove_dir_all(&BACKUP_PATH)?;
+                    remove_dir_all(BACKUP_PATH)?;
                 }
             }
             Err(e) => {
                 // Restore the backup db.
                 if backup {
-                    rename(&BACKUP_PATH, &self.path)?;
+                    rename(BACKUP_PATH, &self.path)?;
                 }
                 return Err(DatabaseError::Internal(e.to_string()));
             }
@@ -129,41 +183,120 @@ impl RocksDB {
         Ok(())
     }
 
-    pub fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator> {
+    /// Iterate the decoded values of the category, skipping the keys no longer
+    /// referenced and the expired values.
+    pub fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
         match *self.db_info {
-            Some(DBInfo { ref db }) => {
-                let iter = {
-                    if let Some(col) = category {
-                        db.iterator_cf_opt(
-                            get_column(db, col).unwrap(),
-                            ReadOptions::default(),
-                            IteratorMode::Start,
-                        )
-                    } else {
-                        db.iterator_opt(IteratorMode::Start, ReadOptions::default())
-                    }
-                };
-                Some(iter)
-            }
+            Some(DBInfo { ref db }) => match category {
+                Some(category) => {
+                    let col = get_column(db, category.clone()).ok()?;
+                    let iter = db.iterator_cf_opt(col, ReadOptions::default(), IteratorMode::Start);
+                    Some(read_entries(&self.config, category, iter))
+                }
+                None => Some(Box::new(
+                    db.iterator_opt(IteratorMode::Start, ReadOptions::default())
+                        .map(|item| item.map_err(DatabaseError::from)),
+                )),
+            },
             None => None,
         }
     }
 
+    fn check_writable(&self) -> Result<()> {
+        if self.read_only {
+            Err(DatabaseError::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    // The category of a merge, which has a merge operator the operand is valid for.
+    fn merge_category(
+        &self,
+        category: Option<DataCategory>,
+        operand: &[u8],
+    ) -> Result<DataCategory> {
+        let category = category.ok_or(DatabaseError::InvalidData)?;
+        self.config
+            .category(&category)
+            .merge_operator()
+            .ok_or(DatabaseError::InvalidData)?
+            .check_operand(operand)?;
+        Ok(category)
+    }
+
+    // Lock the key for a conditional write.
+    fn lock_key(&self, category: &Option<DataCategory>, key: &[u8]) -> Result<MutexGuard<'_, ()>> {
+        let mut hasher = DefaultHasher::new();
+        (category, key).hash(&mut hasher);
+        let lock = &self.key_locks[hasher.finish() as usize % self.key_locks.len()];
+        lock.lock().map_err(|_| map_mutex_err())
+    }
+
+    fn is_ref_counted(&self, category: &DataCategory) -> bool {
+        self.config
+            .categories
+            .get(category)
+            .is_some_and(|c| c.ref_counted)
+    }
+
+    // Get the value from what is stored in the column of the category.
+    fn read_value(
+        &self,
+        category: &DataCategory,
+        stored: Option<Vec<u8>>,
+    ) -> Result<Option<Vec<u8>>> {
+        read_value(&self.config, category, stored)
+    }
+
+    fn batch_put<K: AsRef<[u8]>, V: AsRef<[u8]>>(
+        &self,
+        batch: &mut WriteBatch,
+        col: &ColumnFamily,
+        category: &DataCategory,
+        key: K,
+        value: V,
+    ) {
+        if self.is_ref_counted(category) {
+            batch.merge_cf(col, key, refcount::encode_insert(value.as_ref()));
+        } else {
+            batch.put_cf(
+                col,
+                key,
+                stored_value(&self.config, category, value.as_ref()),
+            );
+        }
+    }
+
+    fn batch_delete<K: AsRef<[u8]>>(
+        &self,
+        batch: &mut WriteBatch,
+        col: &ColumnFamily,
+        category: &DataCategory,
+        key: K,
+    ) {
+        if self.is_ref_counted(category) {
+            batch.merge_cf(col, key, refcount::encode_remove());
+        } else {
+            batch.delete_cf(col, key);
+        }
+    }
+
     #[cfg(test)]
-    fn clean_cf(&mut self) {
+    pub(crate) fn clean_cf(&mut self) {
         let columns: Vec<_> = (0..self.config.category_num.unwrap_or(0))
             .map(|c| format!("col{}", c))
             .collect();
         let columns: Vec<&str> = columns.iter().map(|n| n as &str).collect();
         if let Some(DBInfo { db }) = Arc::get_mut(&mut self.db_info).unwrap() {
             for col in columns.iter() {
-                    db.drop_cf(col).unwrap();
+                db.drop_cf(col).unwrap();
             }
         }
     }
 
     #[cfg(test)]
-    fn clean_db(&self) {
+    pub(crate) fn clean_db(&self) {
         if path_exists(&self.path) {
             remove_dir_all(&self.path).unwrap();
         }
@@ -179,8 +312,8 @@ impl Database for RocksDB {
 
                 let mut value = db.get(&key)?;
                 if let Some(category) = category {
-                    let col = get_column(db, category)?;
-                    value = db.get_cf(col, &key)?;
+                    let col = get_column(db, category.clone())?;
+                    value = self.read_value(&category, db.get_cf(col, &key)?)?;
                 }
                 Ok(value.map(|v| v.to_vec()))
             }
@@ -200,8 +333,8 @@ impl Database for RocksDB {
             for key in keys {
                 let mut value = db.get(&key)?;
                 if let Some(category) = category.clone() {
-                    let col = get_column(db, category)?;
-                    value = db.get_cf(col, &key)?;
+                    let col = get_column(db, category.clone())?;
+                    value = self.read_value(&category, db.get_cf(col, &key)?)?;
                 }
                 values.push(value.map(|v| v.to_vec()));
             }
@@ -211,11 +344,16 @@ impl Database for RocksDB {
     }
 
     fn insert(&self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        self.check_writable()?;
         if let Some(DBInfo { ref db }) = *self.db_info {
             match category {
                 Some(category) => {
-                    let col = get_column(db, category)?;
-                    db.put_cf(col, key, value)?;
+                    let col = get_column(db, category.clone())?;
+                    if self.is_ref_counted(&category) {
+                        db.merge_cf(col, key, refcount::encode_insert(&value))?;
+                    } else {
+                        db.put_cf(col, key, stored_value(&self.config, &category, &value))?;
+                    }
                 }
                 None => db.put(key, value)?,
             }
@@ -230,6 +368,7 @@ impl Database for RocksDB {
         keys: Vec<Vec<u8>>,
         values: Vec<Vec<u8>>,
     ) -> Result<()> {
+        self.check_writable()?;
         if keys.len() != values.len() {
             return Err(DatabaseError::InvalidData);
         }
@@ -240,8 +379,8 @@ impl Database for RocksDB {
             for i in 0..keys.len() {
                 match category.clone() {
                     Some(category) => {
-                        let col = get_column(db, category)?;
-                        batch.put_cf(col, &keys[i], &values[i]);
+                        let col = get_column(db, category.clone())?;
+                        self.batch_put(&mut batch, col, &category, &keys[i], &values[i]);
                     }
                     None => batch.put(&keys[i], &values[i]),
                 }
@@ -258,8 +397,8 @@ impl Database for RocksDB {
                 let key = key.to_vec();
                 let mut value = db.get(&key)?;
                 if let Some(category) = category {
-                    let col = get_column(db, category)?;
-                    value = db.get_cf(col, &key)?;
+                    let col = get_column(db, category.clone())?;
+                    value = self.read_value(&category, db.get_cf(col, &key)?)?;
                 }
 
                 Ok(value.is_some())
@@ -269,12 +408,17 @@ impl Database for RocksDB {
     }
 
     fn remove(&self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        self.check_writable()?;
         if let Some(DBInfo { ref db }) = *self.db_info {
             let key = key.to_vec();
             match category {
                 Some(category) => {
-                    let col = get_column(db, category)?;
-                    db.delete_cf(col, key)?;
+                    let col = get_column(db, category.clone())?;
+                    if self.is_ref_counted(&category) {
+                        db.merge_cf(col, key, refcount::encode_remove())?;
+                    } else {
+                        db.delete_cf(col, key)?;
+                    }
                 }
                 None => db.delete(key)?,
             }
@@ -284,6 +428,7 @@ impl Database for RocksDB {
     }
 
     fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()> {
+        self.check_writable()?;
         if let Some(DBInfo { ref db }) = *self.db_info {
             let keys = keys.to_vec();
             let mut batch = WriteBatch::default();
@@ -291,8 +436,8 @@ impl Database for RocksDB {
             for key in keys {
                 match category.clone() {
                     Some(category) => {
-                        let col = get_column(db, category)?;
-                        batch.delete_cf(col, key);
+                        let col = get_column(db, category.clone())?;
+                        self.batch_delete(&mut batch, col, &category, key);
                     }
                     None => db.delete(key)?,
                 }
@@ -303,11 +448,99 @@ impl Database for RocksDB {
         Ok(())
     }
 
+    fn merge(&self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        self.check_writable()?;
+        let category = self.merge_category(category, &operand)?;
+
+        if let Some(DBInfo { ref db }) = *self.db_info {
+            let col = get_column(db, category)?;
+            db.merge_cf(col, key, operand)?;
+        }
+
+        Ok(())
+    }
+
+    /// Atomic with the other conditional writes, by locking the key.
+    ///
+    /// Return `DatabaseError::InvalidData` for a ref-counted category, whose
+    /// writes add and drop references rather than set the value.
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        self.check_writable()?;
+        if category
+            .as_ref()
+            .is_some_and(|category| self.is_ref_counted(category))
+        {
+            return Err(DatabaseError::InvalidData);
+        }
+        let _guard = self.lock_key(&category, key)?;
+        if self.get(category.clone(), key)?.as_deref() != expected {
+            return Ok(false);
+        }
+        match new {
+            Some(value) => self.insert(category, key.to_vec(), value)?,
+            None => self.remove(category, key)?,
+        }
+        Ok(true)
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        self.check_writable()?;
+        if let Some(DBInfo { ref db }) = *self.db_info {
+            let mut write_batch = WriteBatch::default();
+
+            for op in batch {
+                match op {
+                    BatchOp::Insert {
+                        category: Some(category),
+                        key,
+                        value,
+                    } => {
+                        let col = get_column(db, category.clone())?;
+                        self.batch_put(&mut write_batch, col, &category, key, value);
+                    }
+                    BatchOp::Insert {
+                        category: None,
+                        key,
+                        value,
+                    } => write_batch.put(key, value),
+                    BatchOp::Remove {
+                        category: Some(category),
+                        key,
+                    } => {
+                        let col = get_column(db, category.clone())?;
+                        self.batch_delete(&mut write_batch, col, &category, key);
+                    }
+                    BatchOp::Remove {
+                        category: None,
+                        key,
+                    } => write_batch.delete(key),
+                    BatchOp::Merge {
+                        category,
+                        key,
+                        operand,
+                    } => {
+                        let category = self.merge_category(category, &operand)?;
+                        write_batch.merge_cf(get_column(db, category)?, key, operand);
+                    }
+                }
+            }
+            db.write(write_batch)?;
+        }
+
+        Ok(())
+    }
+
     fn restore(&mut self, new_db: &str) -> Result<()> {
         RocksDB::restore(self, new_db)
     }
 
-    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator> {
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
         RocksDB::iterator(self, category)
     }
 
@@ -316,6 +549,7 @@ impl Database for RocksDB {
     }
 
     fn flush(&self) -> Result<()> {
+        self.check_writable()?;
         if let Some(DBInfo { ref db }) = *self.db_info {
             db.flush()?;
         }
@@ -324,12 +558,210 @@ impl Database for RocksDB {
     }
 }
 
+// The options to open the database and to write, with the config.
+//
+// Return `DatabaseError::InvalidData` for the options of the whole database set
+// in a category: `max_background_compactions` in its compaction, or the FIFO
+// style without `Config::max_open_files` at -1.
+pub(crate) fn open_options(config: &Config) -> Result<(Options, WriteOptions)> {
+    let category_compactions: Vec<_> = config
+        .categories
+        .values()
+        .filter_map(|c| c.compaction.as_ref())
+        .collect();
+    let fifo = std::iter::once(&config.compaction)
+        .chain(category_compactions.iter().copied())
+        .any(|c| matches!(c.style, CompactionStyle::Fifo { .. }));
+    if (fifo && config.max_open_files != -1)
+        || category_compactions
+            .iter()
+            .any(|c| c.max_background_compactions.is_some())
+    {
+        return Err(DatabaseError::InvalidData);
+    }
+
+    let mut opts = Options::default();
+    opts.set_write_buffer_size(WRITE_BUFFER_SIZE);
+    opts.set_max_background_jobs(BACKGROUND_FLUSHES);
+
+    opts.create_if_missing(true);
+    // If true, any column families that didn't exist when opening the database will be created.
+    opts.create_missing_column_families(true);
+
+    let block_opts = BlockBasedOptions::default();
+    opts.set_block_based_table_factory(&block_opts);
+
+    opts.set_max_open_files(config.max_open_files);
+    opts.set_use_fsync(false);
+    set_compaction(&mut opts, &config.compaction);
+    if let Some(compactions) = config.compaction.max_background_compactions {
+        opts.set_max_background_jobs(compactions);
+    }
+
+    let mut write_opts = WriteOptions::default();
+    if !config.wal {
+        write_opts.disable_wal(true);
+    }
+
+    Ok((opts, write_opts))
+}
+
+// Set the compaction style and its options.
+fn set_compaction(opts: &mut Options, compaction: &Compaction) {
+    opts.set_target_file_size_base(compaction.target_file_size_base);
+    if let Some(level_multiplier) = compaction.max_bytes_for_level_multiplier {
+        opts.set_max_bytes_for_level_multiplier(level_multiplier);
+    }
+
+    match compaction.style {
+        CompactionStyle::Level => opts.set_compaction_style(DBCompactionStyle::Level),
+        CompactionStyle::Universal {
+            max_size_amplification_percent,
+        } => {
+            let mut universal_opts = UniversalCompactOptions::default();
+            universal_opts.set_max_size_amplification_percent(max_size_amplification_percent);
+            opts.set_compaction_style(DBCompactionStyle::Universal);
+            opts.set_universal_compaction_options(&universal_opts);
+        }
+        CompactionStyle::Fifo {
+            max_table_files_size,
+        } => {
+            let mut fifo_opts = FifoCompactOptions::default();
+            fifo_opts.set_max_table_files_size(max_table_files_size);
+            opts.set_compaction_style(DBCompactionStyle::Fifo);
+            opts.set_fifo_compaction_options(&fifo_opts);
+        }
+    }
+}
+
+// Enable the blob files.
+fn set_blob(opts: &mut Options, blob: &BlobConfig) {
+    opts.set_enable_blob_files(true);
+    opts.set_min_blob_size(blob.min_blob_size);
+    opts.set_blob_file_size(blob.blob_file_size);
+    opts.set_blob_compression_type(match blob.compression {
+        BlobCompression::None => DBCompressionType::None,
+        BlobCompression::Snappy => DBCompressionType::Snappy,
+        BlobCompression::Lz4 => DBCompressionType::Lz4,
+        BlobCompression::Zstd => DBCompressionType::Zstd,
+    });
+    opts.set_enable_blob_gc(blob.enable_gc);
+    opts.set_blob_gc_age_cutoff(blob.gc_age_cutoff);
+}
+
+// The descriptors of the columns of the categories.
+pub(crate) fn column_descriptors(opts: &Options, config: &Config) -> Vec<ColumnFamilyDescriptor> {
+    let columns: Vec<_> = (0..config.category_num.unwrap_or(0))
+        .map(|c| format!("col{}", c))
+        .collect();
+    debug!("[database] Columns: {:?}", columns);
+
+    columns
+        .iter()
+        .map(|col| ColumnFamilyDescriptor::new(col, column_options(opts, config, col)))
+        .collect()
+}
+
+// Get the value from what is stored in the column of the category.
+pub(crate) fn read_value(
+    config: &Config,
+    category: &DataCategory,
+    stored: Option<Vec<u8>>,
+) -> Result<Option<Vec<u8>>> {
+    let category_config = match (stored.as_ref(), config.categories.get(category)) {
+        (Some(_), Some(category_config)) => category_config,
+        _ => return Ok(stored),
+    };
+    let stored = stored.unwrap_or_default();
+    if category_config.ref_counted {
+        refcount::decode_value(&stored)
+    } else if let Some(ttl) = category_config.ttl() {
+        ttl.decode(stored)
+    } else {
+        Ok(Some(stored))
+    }
+}
+
+// Decode the stored values of the entries of the category, skipping the ones
+// without a value.
+pub(crate) fn read_entries<'a, I>(
+    config: &'a Config,
+    category: DataCategory,
+    iter: I,
+) -> DBIterator<'a>
+where
+    I: Iterator<Item = std::result::Result<(Box<[u8]>, Box<[u8]>), rocksdb::Error>> + 'a,
+{
+    Box::new(iter.filter_map(move |item| {
+        item.map_err(DatabaseError::from)
+            .and_then(|(key, stored)| {
+                let value = read_value(config, &category, Some(stored.into_vec()))?;
+                Ok(value.map(|value| (key, value.into_boxed_slice())))
+            })
+            .transpose()
+    }))
+}
+
+// What is stored in the column of the category for the value, if it is not ref-counted.
+pub(crate) fn stored_value(config: &Config, category: &DataCategory, value: &[u8]) -> Vec<u8> {
+    match config.categories.get(category).and_then(|c| c.ttl()) {
+        Some(ttl) => ttl.encode(value),
+        None => value.to_vec(),
+    }
+}
+
+// Refuse the ref-counted categories which hold plain values, written before they
+// were ref-counted. Only the first entry is checked, and the other plain values
+// fail the reads.
+fn check_ref_counted(db: &DB, config: &Config) -> Result<()> {
+    for (category, category_config) in config.categories.iter() {
+        let col = match db.cf_handle(map_columns(category.clone())) {
+            Some(col) if category_config.ref_counted => col,
+            _ => continue,
+        };
+        if let Some(item) = db.iterator_cf(col, IteratorMode::Start).next() {
+            let (_, value) = item?;
+            if !refcount::is_record(&value) {
+                return Err(DatabaseError::InvalidData);
+            }
+        }
+    }
+    Ok(())
+}
+
 // Get the column from the data category.
 fn get_column(db: &DB, category: DataCategory) -> Result<&ColumnFamily> {
     db.cf_handle(map_columns(category))
         .ok_or(DatabaseError::NotFound)
 }
 
+// The options of a column, with the configuration of its category.
+fn column_options(opts: &Options, config: &Config, col: &str) -> Options {
+    let mut opts = opts.clone();
+    for (category, category_config) in config.categories.iter() {
+        if map_columns(category.clone()) != col {
+            continue;
+        }
+        if let Some(ref compaction) = category_config.compaction {
+            set_compaction(&mut opts, compaction);
+        }
+        if let Some(ref blob) = category_config.blob {
+            set_blob(&mut opts, blob);
+        }
+        if category_config.ref_counted {
+            opts.set_merge_operator(
+                refcount::MERGE_OPERATOR_NAME,
+                refcount::full_merge,
+                refcount::partial_merge,
+            );
+        } else if let Some(operator) = category_config.merge_operator() {
+            opts.set_merge_operator_associative(operator.name(), operator.merge_fn());
+        }
+        filter::set_column_filter(&mut opts, category_config);
+    }
+    opts
+}
+
 // Check the path exists.
 fn path_exists(path: &str) -> bool {
     metadata(Path::new(path)).is_ok()
@@ -337,12 +769,22 @@ fn path_exists(path: &str) -> bool {
 
 #[cfg(test)]
 mod tests {
-    use super::{Config, RocksDB};
+    use super::{map_columns, Config, DBInfo, RocksDB};
+    use crate::batch::Batch;
+    use crate::columns::NUM_COLUMNS;
+    use crate::config::{BlobCompression, BlobConfig, CategoryConfig, Compaction, CompactionStyle};
     use crate::database::{DataCategory, Database};
     use crate::error::DatabaseError;
+    use crate::filter::{CompactionFilter, FilterDecision};
     use crate::rocksdb::{path_exists, BACKUP_PATH};
-    use crate::test::{batch_op, insert_get_contains_remove};
-    use std::fs::{create_dir, remove_dir_all};
+    use crate::test::{
+        batch_op, compare_and_swap, insert_get_contains_remove, merge, merge_config, write_batch,
+    };
+    use crate::ttl::{HeightClock, Ttl};
+    use std::fs::{create_dir, read_dir, remove_dir_all};
+    use std::slice::from_ref;
+    use std::sync::Arc;
+    use std::thread;
 
     #[test]
     fn test_insert_get_contains_remove_with_category() {
@@ -385,6 +827,402 @@ mod tests {
         db.clean_db();
     }
 
+    #[test]
+    fn test_compare_and_swap() {
+        let cfg = Config::with_category_num(Some(1));
+        let mut db = RocksDB::open("rocksdb_test/compare_and_swap", &cfg).unwrap();
+
+        compare_and_swap(&db, None);
+        compare_and_swap(&db, Some(DataCategory::State));
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut db = RocksDB::open("rocksdb_test/merge", &merge_config()).unwrap();
+
+        merge(&db);
+
+        // A malformed value is taken as 0, so that the reads do not fail.
+        let counter = Some(DataCategory::State);
+        let data = b"malformed".to_vec();
+        db.insert(counter.clone(), data.clone(), b"1".to_vec())
+            .unwrap();
+        db.merge(counter.clone(), data.clone(), 2u64.to_le_bytes().to_vec())
+            .unwrap();
+        assert_eq!(
+            db.get(counter, &data),
+            Ok(Some(2u64.to_le_bytes().to_vec()))
+        );
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_ttl() {
+        let clock = Arc::new(HeightClock::new(1));
+        let mut cfg = Config::with_category_num(NUM_COLUMNS);
+        cfg.categories.insert(
+            DataCategory::Trace,
+            CategoryConfig {
+                ttl: Some(Ttl::blocks(2, Arc::clone(&clock))),
+                ..Default::default()
+            },
+        );
+        let mut db = RocksDB::open("rocksdb_test/ttl", &cfg).unwrap();
+        let category = Some(DataCategory::Trace);
+        let data = b"test".to_vec();
+
+        db.insert(category.clone(), data.clone(), data.clone())
+            .unwrap();
+        let mut batch = Batch::new();
+        batch.insert(category.clone(), b"batch".to_vec(), data.clone());
+        db.write(batch).unwrap();
+        clock.set(2);
+        assert_eq!(
+            db.get_batch(category.clone(), &[data.clone(), b"batch".to_vec()]),
+            Ok(vec![Some(data.clone()), Some(data.clone())])
+        );
+        let entries: Vec<_> = db
+            .iterator(category.clone())
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![
+                (b"batch".to_vec().into(), data.clone().into()),
+                (data.clone().into(), data.clone().into()),
+            ]
+        );
+
+        // Expired
+        clock.set(3);
+        assert_eq!(db.get(category.clone(), &data), Ok(None));
+        assert_eq!(db.contains(category.clone(), b"batch"), Ok(false));
+        assert_eq!(db.iterator(category).unwrap().count(), 0);
+
+        // Removed by the compaction.
+        if let Some(DBInfo { ref db }) = *db.db_info {
+            let col = db.cf_handle(map_columns(DataCategory::Trace)).unwrap();
+            db.compact_range_cf(col, None::<&[u8]>, None::<&[u8]>);
+            assert_eq!(db.get_cf(col, &data), Ok(None));
+        }
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_compaction_style() {
+        let mut cfg = Config::with_category_num(NUM_COLUMNS);
+        // FIFO compaction keeps all the files open.
+        cfg.max_open_files = -1;
+        for (category, style) in [
+            (
+                DataCategory::Bodies,
+                CompactionStyle::Fifo {
+                    max_table_files_size: 1024 * 1024,
+                },
+            ),
+            (
+                DataCategory::Trace,
+                CompactionStyle::Universal {
+                    max_size_amplification_percent: 200,
+                },
+            ),
+        ] {
+            cfg.categories.insert(
+                category,
+                CategoryConfig {
+                    compaction: Some(Compaction {
+                        style,
+                        ..Default::default()
+                    }),
+                    ..Default::default()
+                },
+            );
+        }
+        let mut db = RocksDB::open("rocksdb_test/compaction_style", &cfg).unwrap();
+
+        insert_get_contains_remove(&db, Some(DataCategory::Bodies));
+        insert_get_contains_remove(&db, Some(DataCategory::Trace));
+        db.flush().unwrap();
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_compaction_options_error() {
+        let path = "rocksdb_test/compaction_options_error";
+        // FIFO compaction without all the files open
+        let mut cfg = Config::with_category_num(NUM_COLUMNS);
+        cfg.categories.insert(
+            DataCategory::Bodies,
+            CategoryConfig {
+                compaction: Some(Compaction {
+                    style: CompactionStyle::Fifo {
+                        max_table_files_size: 1024 * 1024,
+                    },
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            RocksDB::open(path, &cfg).err(),
+            Some(DatabaseError::InvalidData)
+        );
+
+        // Background compactions of a category
+        let mut cfg = Config::with_category_num(NUM_COLUMNS);
+        cfg.categories.insert(
+            DataCategory::Bodies,
+            CategoryConfig {
+                compaction: Some(Compaction {
+                    max_background_compactions: Some(4),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            RocksDB::open(path, &cfg).err(),
+            Some(DatabaseError::InvalidData)
+        );
+        assert!(!path_exists(path));
+    }
+
+    #[test]
+    fn test_compaction_filter() {
+        let mut cfg = Config::with_category_num(NUM_COLUMNS);
+        cfg.categories.insert(
+            DataCategory::Extra,
+            CategoryConfig {
+                compaction_filter: Some(CompactionFilter::new(|key, _value| {
+                    if key.starts_with(b"old-") {
+                        FilterDecision::Remove
+                    } else if key.starts_with(b"change-") {
+                        FilterDecision::Change(b"changed".to_vec())
+                    } else {
+                        FilterDecision::Keep
+                    }
+                })),
+                ..Default::default()
+            },
+        );
+        let mut db = RocksDB::open("rocksdb_test/compaction_filter", &cfg).unwrap();
+        let category = Some(DataCategory::Extra);
+        let keys = vec![b"old-1".to_vec(), b"change-1".to_vec(), b"new-1".to_vec()];
+        db.insert_batch(category.clone(), keys.clone(), keys.clone())
+            .unwrap();
+
+        if let Some(DBInfo { ref db }) = *db.db_info {
+            let col = db.cf_handle(map_columns(DataCategory::Extra)).unwrap();
+            db.compact_range_cf(col, None::<&[u8]>, None::<&[u8]>);
+        }
+        assert_eq!(
+            db.get_batch(category, &keys),
+            Ok(vec![
+                None,
+                Some(b"changed".to_vec()),
+                Some(b"new-1".to_vec())
+            ])
+        );
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_concurrent_compaction_filter() {
+        let filter = CompactionFilter::new(|key, _value| {
+            FilterDecision::Change([b"changed-", key].concat())
+        });
+        let categories = [DataCategory::Extra, DataCategory::Trace];
+        let mut cfg = Config::with_category_num(NUM_COLUMNS);
+        for category in categories.iter() {
+            cfg.categories.insert(
+                category.clone(),
+                CategoryConfig {
+                    compaction_filter: Some(filter.clone()),
+                    ..Default::default()
+                },
+            );
+        }
+        let mut db = RocksDB::open("rocksdb_test/concurrent_compaction_filter", &cfg).unwrap();
+        let keys: Vec<Vec<u8>> = (0..10000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        for category in categories.iter() {
+            for chunk in keys.chunks(1000) {
+                db.insert_batch(Some(category.clone()), chunk.to_vec(), chunk.to_vec())
+                    .unwrap();
+                db.flush().unwrap();
+            }
+        }
+
+        // Compact two ranges of each column at the same time.
+        if let Some(DBInfo { ref db }) = *db.db_info {
+            let middle = keys[keys.len() / 2].clone();
+            thread::scope(|s| {
+                for category in categories.iter() {
+                    for range in [(None, Some(&middle)), (Some(&middle), None)] {
+                        s.spawn(move || {
+                            let col = db.cf_handle(map_columns(category.clone())).unwrap();
+                            db.compact_range_cf(col, range.0, range.1);
+                        });
+                    }
+                }
+            });
+        }
+        let expected: Vec<_> = keys
+            .iter()
+            .map(|key| Some([b"changed-", &key[..]].concat()))
+            .collect();
+        for category in categories.iter() {
+            assert_eq!(
+                db.get_batch(Some(category.clone()), &keys),
+                Ok(expected.clone())
+            );
+        }
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_blob() {
+        let mut cfg = Config::with_category_num(NUM_COLUMNS);
+        cfg.categories.insert(
+            DataCategory::Bodies,
+            CategoryConfig {
+                blob: Some(BlobConfig {
+                    min_blob_size: 16,
+                    compression: BlobCompression::Lz4,
+                    ..Default::default()
+                }),
+                ..Default::default()
+            },
+        );
+        let mut db = RocksDB::open("rocksdb_test/blob", &cfg).unwrap();
+        let category = Some(DataCategory::Bodies);
+        let body = vec![7u8; 64 * 1024];
+
+        db.insert(category.clone(), b"large".to_vec(), body.clone())
+            .unwrap();
+        db.insert(category.clone(), b"small".to_vec(), b"test".to_vec())
+            .unwrap();
+        if let Some(DBInfo { ref db }) = *db.db_info {
+            let col = db.cf_handle(map_columns(DataCategory::Bodies)).unwrap();
+            db.flush_cf(col).unwrap();
+        }
+        // The large value is in a blob file.
+        let blob_files = read_dir("rocksdb_test/blob")
+            .unwrap()
+            .filter(|entry| {
+                let path = entry.as_ref().unwrap().path();
+                path.extension().is_some_and(|ext| ext == "blob")
+            })
+            .count();
+        assert_eq!(blob_files, 1);
+        assert_eq!(
+            db.get_batch(category, &[b"large".to_vec(), b"small".to_vec()]),
+            Ok(vec![Some(body), Some(b"test".to_vec())])
+        );
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_open_read_only() {
+        let cfg = Config::with_category_num(Some(1));
+        let path = "rocksdb_test/open_read_only";
+        let category = Some(DataCategory::State);
+        let data = b"test".to_vec();
+        let mut db = RocksDB::open(path, &cfg).unwrap();
+        db.insert(category.clone(), data.clone(), data.clone())
+            .unwrap();
+
+        // Opened while the database is open.
+        let mut read_only = RocksDB::open_read_only(path, &cfg).unwrap();
+        assert_eq!(
+            read_only.get(category.clone(), &data),
+            Ok(Some(data.clone()))
+        );
+        assert_eq!(
+            read_only.insert(category.clone(), data.clone(), data.clone()),
+            Err(DatabaseError::ReadOnly)
+        );
+        assert_eq!(
+            read_only.remove(category.clone(), &data),
+            Err(DatabaseError::ReadOnly)
+        );
+        assert_eq!(
+            read_only.insert_if_absent(category.clone(), b"new".to_vec(), data.clone()),
+            Err(DatabaseError::ReadOnly)
+        );
+        assert_eq!(read_only.write(Batch::new()), Err(DatabaseError::ReadOnly));
+        assert_eq!(read_only.restore("new_db"), Err(DatabaseError::ReadOnly));
+        assert_eq!(read_only.flush(), Err(DatabaseError::ReadOnly));
+        read_only.close();
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_open_as_secondary() {
+        let cfg = Config::with_category_num(Some(1));
+        let category = Some(DataCategory::State);
+        let data = b"test".to_vec();
+        let mut db = RocksDB::open("rocksdb_test/open_as_secondary", &cfg).unwrap();
+        db.insert(category.clone(), data.clone(), data.clone())
+            .unwrap();
+
+        let secondary = RocksDB::open_as_secondary(
+            "rocksdb_test/open_as_secondary",
+            "rocksdb_test/open_as_secondary_follower",
+            &cfg,
+        )
+        .unwrap();
+        assert_eq!(
+            secondary.get(category.clone(), &data),
+            Ok(Some(data.clone()))
+        );
+        assert_eq!(
+            secondary.insert(category.clone(), data.clone(), data.clone()),
+            Err(DatabaseError::ReadOnly)
+        );
+        assert_eq!(secondary.flush(), Err(DatabaseError::ReadOnly));
+
+        // Follow the primary.
+        db.remove(category.clone(), &data).unwrap();
+        db.insert(category.clone(), b"new".to_vec(), data.clone())
+            .unwrap();
+        secondary.try_catch_up_with_primary().unwrap();
+        assert_eq!(secondary.contains(category.clone(), &data), Ok(false));
+        assert_eq!(secondary.get(category, b"new"), Ok(Some(data)));
+
+        secondary.clean_db();
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let cfg = Config::with_category_num(Some(1));
+        let mut db = RocksDB::open("rocksdb_test/write_batch", &cfg).unwrap();
+
+        write_batch(&db);
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
     #[test]
     fn test_insert_batch_error_with_category() {
         let cfg = Config::with_category_num(Some(1));
@@ -432,7 +1270,7 @@ mod tests {
         let contents: Vec<_> = db
             .iterator(Some(DataCategory::State))
             .into_iter()
-            .flat_map(|inner| inner)
+            .flatten()
             .collect();
 
         assert_eq!(contents.len(), 2);
@@ -459,11 +1297,7 @@ mod tests {
         )
         .expect("Insert data ok.");
 
-        let contents: Vec<_> = db
-            .iterator(None)
-            .into_iter()
-            .flat_map(|inner| inner)
-            .collect();
+        let contents: Vec<_> = db.iterator(None).into_iter().flatten().collect();
         assert_eq!(contents.len(), 2);
         assert_eq!(&*contents[0].clone().unwrap().0, &*data1);
         assert_eq!(&*contents[0].clone().unwrap().1, &*data1);
@@ -554,4 +1388,107 @@ mod tests {
         remove_dir_all(BACKUP_PATH).unwrap();
         db.clean_db();
     }
+
+    #[test]
+    fn test_ref_counted() {
+        let mut cfg = Config::with_category_num(Some(1));
+        cfg.categories.insert(
+            DataCategory::State,
+            CategoryConfig {
+                ref_counted: true,
+                ..Default::default()
+            },
+        );
+        let mut db = RocksDB::open("rocksdb_test/ref_counted", &cfg).unwrap();
+        let category = Some(DataCategory::State);
+        let data = b"test".to_vec();
+
+        // Two references
+        db.insert(category.clone(), data.clone(), data.clone())
+            .unwrap();
+        db.insert_batch(category.clone(), vec![data.clone()], vec![data.clone()])
+            .unwrap();
+        db.remove(category.clone(), &data).unwrap();
+        assert_eq!(db.get(category.clone(), &data), Ok(Some(data.clone())));
+        db.remove_batch(category.clone(), from_ref(&data)).unwrap();
+        assert_eq!(db.contains(category.clone(), &data), Ok(false));
+
+        // Iterate the decoded values, skipping the unreferenced keys.
+        let other = b"other".to_vec();
+        db.insert(category.clone(), other.clone(), data.clone())
+            .unwrap();
+        let entries: Vec<_> = db
+            .iterator(category.clone())
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(
+            entries,
+            vec![(
+                other.clone().into_boxed_slice(),
+                data.clone().into_boxed_slice()
+            )]
+        );
+        db.remove(category.clone(), &other).unwrap();
+
+        // Dropping a missing reference does nothing.
+        db.remove(category.clone(), &data).unwrap();
+        db.insert(category.clone(), data.clone(), data.clone())
+            .unwrap();
+        assert_eq!(
+            db.get_batch(category.clone(), from_ref(&data)),
+            Ok(vec![Some(data.clone())])
+        );
+
+        // The writes count references, so they can not be conditional.
+        assert_eq!(
+            db.compare_and_swap(category.clone(), &data, Some(&data), None),
+            Err(DatabaseError::InvalidData)
+        );
+        assert_eq!(
+            db.insert_if_absent(category.clone(), other.clone(), data.clone()),
+            Err(DatabaseError::InvalidData)
+        );
+
+        // Deleted by the compaction after the operands are merged.
+        db.remove(category.clone(), &data).unwrap();
+        if let Some(DBInfo { ref db }) = *db.db_info {
+            let col = db.cf_handle(map_columns(DataCategory::State)).unwrap();
+            db.flush_cf(col).unwrap();
+            db.compact_range_cf(col, None::<&[u8]>, None::<&[u8]>);
+            db.compact_range_cf(col, None::<&[u8]>, None::<&[u8]>);
+            assert_eq!(db.get_cf(col, &data), Ok(None));
+        }
+
+        db.clean_cf();
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_ref_counted_plain_values() {
+        let path = "rocksdb_test/ref_counted_plain_values";
+        let mut cfg = Config::with_category_num(Some(1));
+        let db = RocksDB::open(path, &cfg).unwrap();
+        db.insert(
+            Some(DataCategory::State),
+            b"test".to_vec(),
+            b"a plain value".to_vec(),
+        )
+        .unwrap();
+        drop(db);
+
+        cfg.categories.insert(
+            DataCategory::State,
+            CategoryConfig {
+                ref_counted: true,
+                ..Default::default()
+            },
+        );
+        assert_eq!(
+            RocksDB::open(path, &cfg).err(),
+            Some(DatabaseError::InvalidData)
+        );
+
+        remove_dir_all(path).unwrap();
+    }
 }
@@ -0,0 +1,255 @@
+//! Serve a database over TCP or a Unix domain socket, to be used by `RemoteDB`.
+//!
+//! Each connection is served on its own thread, handling its requests in order.
+//! There is no authentication or encryption, so listen on localhost or on a Unix
+//! socket with the access limited by its file permissions.
+
+use std::io::{self, BufRead, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::net::TcpListener;
+#[cfg(unix)]
+use std::os::unix::net::UnixListener;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use crate::database::{DataCategory, Database, Result};
+use crate::error::DatabaseError;
+use crate::export::write_bytes;
+use crate::protocol::{write_error, write_result, Reply, Request, END, ERR, ITEM};
+
+/// The default limit of the connections served at the same time.
+pub const MAX_CONNECTIONS: usize = 256;
+/// The default timeout of reading and writing a connection.
+pub const TIMEOUT: Duration = Duration::from_secs(300);
+// The pause after failing to accept, e.g. out of file descriptors.
+const ACCEPT_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+pub struct Server<D> {
+    db: Arc<D>,
+    max_connections: usize,
+    timeout: Duration,
+    // The connections being served.
+    connections: Arc<AtomicUsize>,
+}
+
+impl<D: Database + 'static> Server<D> {
+    pub fn new(db: Arc<D>) -> Self {
+        Server {
+            db,
+            max_connections: MAX_CONNECTIONS,
+            timeout: TIMEOUT,
+            connections: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Close the connections beyond the limit as soon as they are accepted.
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections = max_connections;
+        self
+    }
+
+    /// Close the connections idle for the timeout, or blocked on reading or
+    /// writing for it.
+    pub fn with_timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn database(&self) -> &Arc<D> {
+        &self.db
+    }
+
+    /// Serve the connections of the listener. Accepting is retried after
+    /// a failure, until the listener is not usable.
+    pub fn serve_tcp(&self, listener: TcpListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = match accepted(stream)? {
+                Some(stream) => stream,
+                None => continue,
+            };
+            // The connection is dropped if it fails to be set up.
+            let reader = stream
+                .set_nodelay(true)
+                .and_then(|_| stream.set_read_timeout(Some(self.timeout)))
+                .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+                .and_then(|_| stream.try_clone());
+            if let Ok(reader) = reader {
+                self.spawn(reader, stream);
+            }
+        }
+        Ok(())
+    }
+
+    /// Serve the connections of the listener. Accepting is retried after
+    /// a failure, until the listener is not usable.
+    #[cfg(unix)]
+    pub fn serve_unix(&self, listener: UnixListener) -> Result<()> {
+        for stream in listener.incoming() {
+            let stream = match accepted(stream)? {
+                Some(stream) => stream,
+                None => continue,
+            };
+            let reader = stream
+                .set_read_timeout(Some(self.timeout))
+                .and_then(|_| stream.set_write_timeout(Some(self.timeout)))
+                .and_then(|_| stream.try_clone());
+            if let Ok(reader) = reader {
+                self.spawn(reader, stream);
+            }
+        }
+        Ok(())
+    }
+
+    fn spawn<R, W>(&self, reader: R, writer: W)
+    where
+        R: Read + Send + 'static,
+        W: Write + Send + 'static,
+    {
+        let guard = ConnectionGuard::new(Arc::clone(&self.connections));
+        if guard.count > self.max_connections {
+            warn!("[database] Server: too many connections, closing one");
+            return;
+        }
+
+        let db = Arc::clone(&self.db);
+        let spawned = thread::Builder::new().spawn(move || {
+            let _guard = guard;
+            // The connection is gone if the reply can not be written.
+            let _ = serve_connection(&*db, reader, writer);
+        });
+        if let Err(err) = spawned {
+            warn!(
+                "[database] Server: failed to spawn a connection thread: {}",
+                err
+            );
+        }
+    }
+}
+
+// The stream accepted, `None` to retry after a failure which may be temporary.
+fn accepted<S>(stream: io::Result<S>) -> Result<Option<S>> {
+    match stream {
+        Ok(stream) => Ok(Some(stream)),
+        // The listener is not listening.
+        Err(err) if err.kind() == ErrorKind::InvalidInput => Err(err.into()),
+        Err(err) => {
+            warn!("[database] Server: failed to accept a connection: {}", err);
+            if !matches!(
+                err.kind(),
+                ErrorKind::ConnectionAborted | ErrorKind::ConnectionReset | ErrorKind::Interrupted
+            ) {
+                thread::sleep(ACCEPT_RETRY_DELAY);
+            }
+            Ok(None)
+        }
+    }
+}
+
+// Count a connection while it is served.
+struct ConnectionGuard {
+    connections: Arc<AtomicUsize>,
+    // The connections with this one.
+    count: usize,
+}
+
+impl ConnectionGuard {
+    fn new(connections: Arc<AtomicUsize>) -> Self {
+        let count = connections.fetch_add(1, Ordering::SeqCst) + 1;
+        ConnectionGuard { connections, count }
+    }
+}
+
+impl Drop for ConnectionGuard {
+    fn drop(&mut self) {
+        self.connections.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+fn serve_connection<D: Database, R: Read, W: Write>(db: &D, reader: R, writer: W) -> Result<()> {
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    loop {
+        // The client closed the connection, or was idle for the timeout.
+        match reader.fill_buf() {
+            Ok(buf) if !buf.is_empty() => (),
+            _ => return Ok(()),
+        }
+        let request = match Request::read_from(&mut reader) {
+            Ok(request) => request,
+            // The client sent a malformed or too large request which the requests
+            // after it can not be framed from, or was too slow to send it.
+            Err(err) => {
+                write_result(&mut writer, &Err(err))?;
+                writer.flush()?;
+                return Ok(());
+            }
+        };
+        match request {
+            Request::Iterate { category } => iterate(db, category, &mut writer)?,
+            request => write_result(&mut writer, &handle(db, request))?,
+        }
+        writer.flush()?;
+    }
+}
+
+fn handle<D: Database>(db: &D, request: Request) -> Result<Reply> {
+    match request {
+        Request::Get { category, key } => db.get(category, &key).map(Reply::Value),
+        Request::GetBatch { category, keys } => db.get_batch(category, &keys).map(Reply::Values),
+        Request::InsertBatch {
+            category,
+            keys,
+            values,
+        } => db.insert_batch(category, keys, values).map(|_| Reply::Done),
+        Request::RemoveBatch { category, keys } => {
+            db.remove_batch(category, &keys).map(|_| Reply::Done)
+        }
+        Request::Merge {
+            category,
+            key,
+            operand,
+        } => db.merge(category, key, operand).map(|_| Reply::Done),
+        Request::CompareAndSwap {
+            category,
+            key,
+            expected,
+            new,
+        } => db
+            .compare_and_swap(category, &key, expected.as_deref(), new)
+            .map(Reply::Swapped),
+        Request::Write(batch) => db.write(batch).map(|_| Reply::Done),
+        Request::Flush => db.flush().map(|_| Reply::Done),
+        Request::Iterate { .. } => Err(DatabaseError::InvalidData),
+    }
+}
+
+// Stream the entries, with the backpressure of the connection.
+fn iterate<D: Database, W: Write>(
+    db: &D,
+    category: Option<DataCategory>,
+    writer: &mut W,
+) -> Result<()> {
+    let iter = match db.iterator(category) {
+        Some(iter) => iter,
+        None => {
+            writer.write_all(&[ERR])?;
+            return write_error(writer, &DatabaseError::NotFound);
+        }
+    };
+    for item in iter {
+        match item {
+            Ok((key, value)) => {
+                writer.write_all(&[ITEM])?;
+                write_bytes(writer, &key)?;
+                write_bytes(writer, &value)?;
+            }
+            Err(err) => {
+                writer.write_all(&[ERR])?;
+                return write_error(writer, &err);
+            }
+        }
+    }
+    writer.write_all(&[END])?;
+    Ok(())
+}
@@ -0,0 +1,671 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::{create_dir_all, metadata, remove_file, rename};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+use crate::batch::{Batch, BatchOp};
+use crate::columns::map_columns;
+use crate::config::Config;
+use crate::database::{DBIterator, DataCategory, Database, Result};
+use crate::error::{map_mutex_err, DatabaseError};
+use crate::merge::MergeOperator;
+use rusqlite::{params, Connection, OptionalExtension, Row, TransactionBehavior};
+
+// The backup db path.
+const BACKUP_PATH: &str = "backup_old_sqlite";
+// The table of the data without category.
+const DEFAULT_TABLE: &str = "default_data";
+// The number of rows the iterator reads in a query.
+const ITER_CHUNK: usize = 1024;
+
+// The categories to create the tables of. Their order does not matter, the
+// columns of `map_columns` decide which are in the config.
+const CATEGORIES: [DataCategory; 9] = [
+    DataCategory::State,
+    DataCategory::Headers,
+    DataCategory::Bodies,
+    DataCategory::Extra,
+    DataCategory::Trace,
+    DataCategory::AccountBloom,
+    DataCategory::Other,
+    DataCategory::Journal,
+    DataCategory::Undo,
+];
+// The files SQLite keeps next to the database in the WAL mode.
+const SIDECAR_SUFFIXES: [&str; 2] = ["-wal", "-shm"];
+
+// A key and its value.
+type Entry = (Box<[u8]>, Box<[u8]>);
+
+/// SQLite implementation of the database, with a table of blob keys and values
+/// for each category, to inspect the data with the SQL tools.
+///
+/// The merge operators of the categories in the config are used, the other
+/// options are specific to RocksDB. The operations are serialized on a single
+/// connection, and each write is a transaction.
+pub struct SqliteDB {
+    // `None` if it is closed.
+    conn: Mutex<Option<Connection>>,
+    // The tables of the categories in the config.
+    tables: HashMap<DataCategory, &'static str>,
+    config: Config,
+    path: String,
+}
+
+impl SqliteDB {
+    /// Open a SQLite with default config.
+    pub fn open_default(path: &str) -> Result<Self> {
+        Self::open(path, &Config::default())
+    }
+
+    /// Open SQLite at the file path with config, creating the tables if they do
+    /// not exist.
+    ///
+    /// A table is created for each of `config.category_num` categories.
+    /// Return `DatabaseError::InvalidData` if a category has a time-to-live,
+    /// which it does not apply.
+    pub fn open(path: &str, config: &Config) -> Result<Self> {
+        config.check_no_ttl()?;
+        if let Some(parent) = Path::new(path).parent() {
+            create_dir_all(parent)?;
+        }
+        let conn = Connection::open(path)?;
+        conn.query_row("PRAGMA journal_mode = WAL", [], |_| Ok(()))?;
+        let category_num = config.category_num.unwrap_or(0) as usize;
+        let columns: HashSet<_> = (0..category_num).map(|c| format!("col{}", c)).collect();
+        let tables: HashMap<_, _> = CATEGORIES
+            .iter()
+            .filter(|category| columns.contains(map_columns((*category).clone())))
+            .map(|category| (category.clone(), map_tables(category)))
+            .collect();
+        for table in tables.values().chain(Some(&DEFAULT_TABLE)) {
+            conn.execute(
+                &format!(
+                    "CREATE TABLE IF NOT EXISTS {} (key BLOB PRIMARY KEY, value BLOB NOT NULL) WITHOUT ROWID",
+                    table
+                ),
+                [],
+            )?;
+        }
+
+        Ok(SqliteDB {
+            conn: Mutex::new(Some(conn)),
+            tables,
+            config: config.clone(),
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn close(&mut self) {
+        if let Ok(conn) = self.conn.get_mut() {
+            if let Some(conn) = conn.take() {
+                let _ = conn.close();
+            }
+        }
+    }
+
+    /// Restore the database from a copy at given path.
+    pub fn restore(&mut self, new_db_path: &str) -> Result<()> {
+        self.close();
+
+        // Backup if the backup_path does not exist.
+        let backup = !path_exists(BACKUP_PATH);
+
+        // Backup the old db
+        if backup {
+            rename_db(&self.path, BACKUP_PATH)?;
+        }
+
+        // Restore the new db.
+        match rename_db(new_db_path, &self.path) {
+            Ok(_) => {
+                // Clean up the backup db.
+                if backup {
+                    remove_db(BACKUP_PATH)?;
+                }
+            }
+            Err(e) => {
+                // Restore the backup db.
+                if backup {
+                    rename_db(BACKUP_PATH, &self.path)?;
+                }
+                return Err(DatabaseError::Internal(e.to_string()));
+            }
+        }
+
+        // Reopen the database.
+        *self = Self::open(&self.path, &self.config)?;
+        Ok(())
+    }
+
+    // The table of the category, `NotFound` if it is not in the config.
+    fn table(&self, category: &Option<DataCategory>) -> Result<&'static str> {
+        match category {
+            Some(category) => self
+                .tables
+                .get(category)
+                .copied()
+                .ok_or(DatabaseError::NotFound),
+            None => Ok(DEFAULT_TABLE),
+        }
+    }
+
+    // The merge operator of the category, which the operand is valid for.
+    fn merge_operator(
+        &self,
+        category: &Option<DataCategory>,
+        operand: &[u8],
+    ) -> Result<MergeOperator> {
+        let operator = category
+            .as_ref()
+            .and_then(|category| self.config.category(category).merge_operator())
+            .ok_or(DatabaseError::InvalidData)?;
+        operator.check_operand(operand)?;
+        Ok(operator)
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Option<Connection>>> {
+        self.conn.lock().map_err(|_| map_mutex_err())
+    }
+
+    // Run the reads on the connection, the default if it is closed.
+    fn view<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Default,
+        F: FnOnce(&Connection) -> Result<T>,
+    {
+        match *self.lock()? {
+            Some(ref conn) => f(conn),
+            None => Ok(T::default()),
+        }
+    }
+
+    // Run the writes in a transaction, which is committed if they succeed.
+    fn update<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Default,
+        F: FnOnce(&Connection) -> Result<T>,
+    {
+        match *self.lock()? {
+            Some(ref mut conn) => {
+                let txn = conn.transaction_with_behavior(TransactionBehavior::Immediate)?;
+                let result = f(&txn)?;
+                txn.commit()?;
+                Ok(result)
+            }
+            None => Ok(T::default()),
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn clean_db(&mut self) {
+        self.close();
+        remove_db(&self.path).unwrap();
+    }
+}
+
+impl Database for SqliteDB {
+    fn get(&self, category: Option<DataCategory>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let table = self.table(&category)?;
+        self.view(|conn| select(conn, table, key))
+    }
+
+    fn get_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: &[Vec<u8>],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        let table = self.table(&category)?;
+        self.view(|conn| keys.iter().map(|key| select(conn, table, key)).collect())
+    }
+
+    fn insert(&self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let table = self.table(&category)?;
+        self.update(|conn| upsert(conn, table, &key, &value))
+    }
+
+    fn insert_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        if keys.len() != values.len() {
+            return Err(DatabaseError::InvalidData);
+        }
+
+        let table = self.table(&category)?;
+        self.update(|conn| {
+            for (key, value) in keys.iter().zip(values.iter()) {
+                upsert(conn, table, key, value)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn contains(&self, category: Option<DataCategory>, key: &[u8]) -> Result<bool> {
+        Ok(self.get(category, key)?.is_some())
+    }
+
+    fn remove(&self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        let table = self.table(&category)?;
+        self.update(|conn| delete(conn, table, key))
+    }
+
+    fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()> {
+        let table = self.table(&category)?;
+        self.update(|conn| {
+            for key in keys {
+                delete(conn, table, key)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn merge(&self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let operator = self.merge_operator(&category, &operand)?;
+        let table = self.table(&category)?;
+        self.update(|conn| merge_value(conn, table, operator, &key, &operand))
+    }
+
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        let table = self.table(&category)?;
+        self.update(|conn| {
+            if select(conn, table, key)?.as_deref() != expected {
+                return Ok(false);
+            }
+            match new {
+                Some(value) => upsert(conn, table, key, &value)?,
+                None => delete(conn, table, key)?,
+            }
+            Ok(true)
+        })
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        // Check the categories and the operands before the transaction.
+        let ops = batch
+            .into_iter()
+            .map(|op| {
+                let (table, operator) = match op {
+                    BatchOp::Insert { ref category, .. } | BatchOp::Remove { ref category, .. } => {
+                        (self.table(category)?, None)
+                    }
+                    BatchOp::Merge {
+                        ref category,
+                        ref operand,
+                        ..
+                    } => (
+                        self.table(category)?,
+                        Some(self.merge_operator(category, operand)?),
+                    ),
+                };
+                Ok((table, operator, op))
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        self.update(|conn| {
+            for (table, operator, op) in ops {
+                match (op, operator) {
+                    (BatchOp::Insert { key, value, .. }, _) => upsert(conn, table, &key, &value)?,
+                    (BatchOp::Remove { key, .. }, _) => delete(conn, table, &key)?,
+                    (BatchOp::Merge { key, operand, .. }, Some(operator)) => {
+                        merge_value(conn, table, operator, &key, &operand)?
+                    }
+                    (BatchOp::Merge { .. }, None) => unreachable!("merges have an operator"),
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn restore(&mut self, new_db: &str) -> Result<()> {
+        SqliteDB::restore(self, new_db)
+    }
+
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
+        let table = self.table(&category).ok()?;
+        self.lock().ok()?.as_ref()?;
+        Some(Box::new(ChunkIter {
+            sqlite: self,
+            table,
+            last: None,
+            chunk: VecDeque::new(),
+            done: false,
+        }))
+    }
+
+    fn close(&mut self) {
+        SqliteDB::close(self)
+    }
+
+    fn flush(&self) -> Result<()> {
+        self.view(|conn| {
+            conn.query_row("PRAGMA wal_checkpoint(FULL)", [], |_| Ok(()))?;
+            Ok(())
+        })
+    }
+}
+
+// Iterate a table in the order of the keys, with a query for each chunk, so that
+// the connection is not held between the calls. The keys written after it
+// starts may be seen.
+struct ChunkIter<'a> {
+    sqlite: &'a SqliteDB,
+    table: &'static str,
+    // The last key read.
+    last: Option<Vec<u8>>,
+    chunk: VecDeque<Entry>,
+    done: bool,
+}
+
+impl<'a> ChunkIter<'a> {
+    fn read_chunk(&mut self) -> Result<()> {
+        let (table, last) = (self.table, self.last.as_deref());
+        let chunk: VecDeque<Entry> = self.sqlite.view(|conn| {
+            let chunk = match last {
+                Some(last) => conn
+                    .prepare_cached(&format!(
+                        "SELECT key, value FROM {} WHERE key > ?1 ORDER BY key LIMIT ?2",
+                        table
+                    ))?
+                    .query_map(params![last, ITER_CHUNK as i64], read_entry)?
+                    .collect::<rusqlite::Result<_>>()?,
+                None => conn
+                    .prepare_cached(&format!(
+                        "SELECT key, value FROM {} ORDER BY key LIMIT ?1",
+                        table
+                    ))?
+                    .query_map([ITER_CHUNK as i64], read_entry)?
+                    .collect::<rusqlite::Result<_>>()?,
+            };
+            Ok(chunk)
+        })?;
+
+        self.done = chunk.len() < ITER_CHUNK;
+        if let Some((key, _)) = chunk.back() {
+            self.last = Some(key.to_vec());
+        }
+        self.chunk = chunk;
+        Ok(())
+    }
+}
+
+impl<'a> Iterator for ChunkIter<'a> {
+    type Item = Result<Entry>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.chunk.is_empty() && !self.done {
+            if let Err(e) = self.read_chunk() {
+                self.done = true;
+                return Some(Err(e));
+            }
+        }
+        self.chunk.pop_front().map(Ok)
+    }
+}
+
+fn read_entry(row: &Row) -> rusqlite::Result<Entry> {
+    let key: Vec<u8> = row.get(0)?;
+    let value: Vec<u8> = row.get(1)?;
+    Ok((key.into(), value.into()))
+}
+
+fn select(conn: &Connection, table: &str, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let sql = format!("SELECT value FROM {} WHERE key = ?1", table);
+    let value = conn
+        .prepare_cached(&sql)?
+        .query_row([key], |row| row.get(0))
+        .optional()?;
+    Ok(value)
+}
+
+fn upsert(conn: &Connection, table: &str, key: &[u8], value: &[u8]) -> Result<()> {
+    let sql = format!(
+        "INSERT OR REPLACE INTO {} (key, value) VALUES (?1, ?2)",
+        table
+    );
+    conn.prepare_cached(&sql)?.execute(params![key, value])?;
+    Ok(())
+}
+
+fn merge_value(
+    conn: &Connection,
+    table: &str,
+    operator: MergeOperator,
+    key: &[u8],
+    operand: &[u8],
+) -> Result<()> {
+    let existing = select(conn, table, key)?;
+    let value = operator.apply(existing.as_deref(), vec![operand])?;
+    upsert(conn, table, key, &value)
+}
+
+fn delete(conn: &Connection, table: &str, key: &[u8]) -> Result<()> {
+    let sql = format!("DELETE FROM {} WHERE key = ?1", table);
+    conn.prepare_cached(&sql)?.execute([key])?;
+    Ok(())
+}
+
+fn path_exists(path: &str) -> bool {
+    metadata(Path::new(path)).is_ok()
+}
+
+// Rename the database with its sidecar files, removing the stale ones of the
+// destination, which would be applied to the renamed database.
+fn rename_db(from: &str, to: &str) -> std::io::Result<()> {
+    rename(from, to)?;
+    for suffix in SIDECAR_SUFFIXES.iter() {
+        let (from, to) = (format!("{}{}", from, suffix), format!("{}{}", to, suffix));
+        if path_exists(&from) {
+            rename(&from, &to)?;
+        } else if path_exists(&to) {
+            remove_file(&to)?;
+        }
+    }
+    Ok(())
+}
+
+// Remove the database with its sidecar files.
+fn remove_db(path: &str) -> std::io::Result<()> {
+    let sidecars = SIDECAR_SUFFIXES
+        .iter()
+        .map(|suffix| format!("{}{}", path, suffix));
+    for path in Some(path.to_owned()).into_iter().chain(sidecars) {
+        if path_exists(&path) {
+            remove_file(&path)?;
+        }
+    }
+    Ok(())
+}
+
+// The table of the category.
+fn map_tables(category: &DataCategory) -> &'static str {
+    match category {
+        DataCategory::State => "state",
+        DataCategory::Headers => "headers",
+        DataCategory::Bodies => "bodies",
+        DataCategory::Extra => "extra",
+        DataCategory::Trace => "trace",
+        DataCategory::AccountBloom => "account_bloom",
+        DataCategory::Other => "other",
+        DataCategory::Journal => "journal",
+        DataCategory::Undo => "undo",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{path_exists, SqliteDB, BACKUP_PATH, CATEGORIES, ITER_CHUNK};
+    use crate::batch::Batch;
+    use crate::columns::{map_columns, NUM_COLUMNS};
+    use crate::config::Config;
+    use crate::database::{DataCategory, Database};
+    use crate::error::DatabaseError;
+    use crate::test::{
+        batch_op, compare_and_swap, insert_get_contains_remove, merge, merge_config,
+        merge_malformed, write_batch,
+    };
+    use std::fs::{copy, remove_file};
+
+    #[test]
+    fn test_categories() {
+        // Every column has a table.
+        let columns: std::collections::HashSet<_> = CATEGORIES
+            .iter()
+            .map(|category| map_columns(category.clone()))
+            .collect();
+        assert_eq!(columns.len() as u32, NUM_COLUMNS.unwrap());
+    }
+
+    #[test]
+    fn test_insert_get_contains_remove() {
+        let cfg = Config::with_category_num(NUM_COLUMNS);
+        let mut db = SqliteDB::open("sqlite_test/insert_get_contains_remove.db", &cfg).unwrap();
+
+        insert_get_contains_remove(&db, None);
+        insert_get_contains_remove(&db, Some(DataCategory::State));
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_batch_op() {
+        let cfg = Config::with_category_num(NUM_COLUMNS);
+        let mut db = SqliteDB::open("sqlite_test/batch_op.db", &cfg).unwrap();
+
+        batch_op(&db, None);
+        batch_op(&db, Some(DataCategory::State));
+        write_batch(&db);
+        match db.insert_batch(None, vec![b"test".to_vec()], vec![]) {
+            Err(DatabaseError::InvalidData) => (), // pass
+            _ => panic!("should return error DatabaseError::InvalidData"),
+        }
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_compare_and_swap() {
+        let cfg = Config::with_category_num(NUM_COLUMNS);
+        let mut db = SqliteDB::open("sqlite_test/compare_and_swap.db", &cfg).unwrap();
+
+        compare_and_swap(&db, None);
+        compare_and_swap(&db, Some(DataCategory::State));
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_merge() {
+        let mut db = SqliteDB::open("sqlite_test/merge.db", &merge_config()).unwrap();
+
+        merge(&db);
+        merge_malformed(&db);
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_category_not_found() {
+        let mut db = SqliteDB::open_default("sqlite_test/category_not_found.db").unwrap();
+
+        assert_eq!(
+            db.get(Some(DataCategory::State), b"test"),
+            Err(DatabaseError::NotFound)
+        );
+        let mut batch = Batch::new();
+        batch.insert(None, b"test".to_vec(), b"test".to_vec());
+        batch.insert(
+            Some(DataCategory::State),
+            b"test".to_vec(),
+            b"test".to_vec(),
+        );
+        assert_eq!(db.write(batch), Err(DatabaseError::NotFound));
+        assert_eq!(db.get(None, b"test"), Ok(None));
+        assert!(db.iterator(Some(DataCategory::State)).is_none());
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_iterator() {
+        let cfg = Config::with_category_num(NUM_COLUMNS);
+        let mut db = SqliteDB::open("sqlite_test/iterator.db", &cfg).unwrap();
+        let category = Some(DataCategory::State);
+        // More than a chunk
+        let keys: Vec<_> = (0..ITER_CHUNK as u32 * 2 + 1)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        db.insert_batch(category.clone(), keys.clone(), keys.clone())
+            .unwrap();
+        db.insert(None, b"test".to_vec(), b"test".to_vec()).unwrap();
+
+        let contents: Vec<_> = db
+            .iterator(category)
+            .unwrap()
+            .map(|item| item.unwrap())
+            .collect();
+        assert_eq!(contents.len(), keys.len());
+        assert!(contents
+            .iter()
+            .zip(keys.iter())
+            .all(|((key, value), expected)| **key == **expected && **value == **expected));
+
+        let contents: Vec<_> = db.iterator(None).into_iter().flatten().collect();
+        assert_eq!(contents, vec![Ok((b"test"[..].into(), b"test"[..].into()))]);
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_close() {
+        let mut db = SqliteDB::open_default("sqlite_test/close.db").unwrap();
+        let data = b"test".to_vec();
+        db.insert(None, data.clone(), data.clone()).unwrap();
+        assert_eq!(db.contains(None, &data), Ok(true));
+        db.flush().unwrap();
+        db.close();
+        // Can not query
+        assert_eq!(db.contains(None, &data), Ok(false));
+        assert!(db.iterator(None).is_none());
+
+        // Can open it again and query
+        let mut db = SqliteDB::open_default("sqlite_test/close.db").unwrap();
+        assert_eq!(db.contains(None, &data), Ok(true));
+        db.clean_db();
+    }
+
+    #[test]
+    fn test_restore() {
+        if path_exists(BACKUP_PATH) {
+            remove_file(BACKUP_PATH).unwrap();
+        }
+        let mut db = SqliteDB::open_default("sqlite_test/restore.db").unwrap();
+        let new_path = "sqlite_test/restore_new_db.db";
+        let mut new_db = SqliteDB::open_default(new_path).unwrap();
+        let data = b"test".to_vec();
+        new_db.insert(None, data.clone(), data.clone()).unwrap();
+        new_db.close();
+
+        assert_eq!(db.contains(None, &data), Ok(false));
+        assert_eq!(db.restore(new_path), Ok(()));
+        assert_eq!(db.contains(None, &data), Ok(true));
+        assert!(!path_exists(new_path));
+
+        // A copy of a database while it is open, with the data only in the WAL.
+        let open_path = "sqlite_test/restore_open_db.db";
+        let mut open_db = SqliteDB::open_default(open_path).unwrap();
+        let data = b"wal".to_vec();
+        open_db.insert(None, data.clone(), data.clone()).unwrap();
+        copy(open_path, new_path).unwrap();
+        copy(format!("{}-wal", open_path), format!("{}-wal", new_path)).unwrap();
+        open_db.clean_db();
+
+        assert_eq!(db.restore(new_path), Ok(()));
+        assert_eq!(db.contains(None, &data), Ok(true));
+        assert!(!path_exists(&format!("{}-wal", new_path)));
+        db.clean_db();
+        assert!(!path_exists("sqlite_test/restore.db-wal"));
+    }
+}
@@ -1,5 +1,10 @@
+use crate::batch::Batch;
+use crate::columns::NUM_COLUMNS;
+use crate::config::{CategoryConfig, Config};
 use crate::database::{DataCategory, Database};
 use crate::error::DatabaseError;
+use crate::merge::MergeOperator;
+use crate::transaction::Transaction;
 
 #[cfg(test)]
 fn get_value<K: AsRef<[u8]>, D: Database>(
@@ -64,3 +69,232 @@ pub fn batch_op<D: Database>(db: &D, category: Option<DataCategory>) {
     assert_eq!(get_value(db, data1, category.clone()), Ok(None));
     assert_eq!(get_value(db, data2, category.clone()), Ok(None));
 }
+
+#[cfg(test)]
+pub fn write_batch<D: Database>(db: &D) {
+    let data1 = b"test1".to_vec();
+    let data2 = b"test2".to_vec();
+    db.insert(None, data1.clone(), data1.clone()).unwrap();
+
+    let mut batch = Batch::new();
+    batch.remove(None, data1.clone());
+    batch.insert(None, data2.clone(), data2.clone());
+    batch.insert(Some(DataCategory::State), data1.clone(), data2.clone());
+    db.write(batch).unwrap();
+
+    assert_eq!(get_value(db, data1.clone(), None), Ok(None));
+    assert_eq!(get_value(db, data2.clone(), None), Ok(Some(data2.clone())));
+    assert_eq!(
+        get_value(db, data1.clone(), Some(DataCategory::State)),
+        Ok(Some(data2.clone()))
+    );
+
+    db.remove(None, &data2).unwrap();
+    db.remove(Some(DataCategory::State), &data1).unwrap();
+}
+
+#[cfg(test)]
+pub fn transaction_conflict<T: Transaction, F: Fn() -> T>(begin: F) {
+    let category = Some(DataCategory::State);
+    let data1 = b"test1".to_vec();
+    let data2 = b"test2".to_vec();
+
+    // Read your own writes.
+    let mut txn = begin();
+    txn.put(category.clone(), data1.clone(), data1.clone())
+        .unwrap();
+    txn.put(None, data2.clone(), data2.clone()).unwrap();
+    txn.delete(None, &data2).unwrap();
+    assert_eq!(
+        txn.get_for_update(category.clone(), &data1),
+        Ok(Some(data1.clone()))
+    );
+    assert_eq!(txn.get_for_update(None, &data2), Ok(None));
+    txn.commit().unwrap();
+
+    // The key read is changed by another transaction.
+    let mut txn1 = begin();
+    let mut txn2 = begin();
+    assert_eq!(
+        txn1.get_for_update(category.clone(), &data1),
+        Ok(Some(data1.clone()))
+    );
+    txn2.put(category.clone(), data1.clone(), data2.clone())
+        .unwrap();
+    txn2.commit().unwrap();
+    txn1.put(None, data2.clone(), data2.clone()).unwrap();
+    assert_eq!(txn1.commit(), Err(DatabaseError::Conflict));
+
+    // The writes of the failed transaction are discarded.
+    let mut txn = begin();
+    assert_eq!(txn.get_for_update(None, &data2), Ok(None));
+    assert_eq!(
+        txn.get_for_update(category.clone(), &data1),
+        Ok(Some(data2.clone()))
+    );
+
+    // Writing without reading does not conflict.
+    let mut other = begin();
+    other.put(category, data1.clone(), data1).unwrap();
+    other.commit().unwrap();
+    txn.put(None, data2.clone(), data2).unwrap();
+    assert_eq!(txn.commit(), Err(DatabaseError::Conflict));
+}
+
+#[cfg(test)]
+pub fn compare_and_swap<D: Database>(db: &D, category: Option<DataCategory>) {
+    let data1 = b"test1".to_vec();
+    let data2 = b"test2".to_vec();
+
+    // Absent
+    assert_eq!(
+        db.compare_and_swap(category.clone(), &data1, Some(&data1), Some(data2.clone())),
+        Ok(false)
+    );
+    assert_eq!(
+        db.insert_if_absent(category.clone(), data1.clone(), data1.clone()),
+        Ok(true)
+    );
+    assert_eq!(
+        db.insert_if_absent(category.clone(), data1.clone(), data2.clone()),
+        Ok(false)
+    );
+    assert_eq!(
+        get_value(db, &data1, category.clone()),
+        Ok(Some(data1.clone()))
+    );
+
+    // Swap and remove
+    assert_eq!(
+        db.compare_and_swap(category.clone(), &data1, None, Some(data2.clone())),
+        Ok(false)
+    );
+    assert_eq!(
+        db.compare_and_swap(category.clone(), &data1, Some(&data1), Some(data2.clone())),
+        Ok(true)
+    );
+    assert_eq!(
+        get_value(db, &data1, category.clone()),
+        Ok(Some(data2.clone()))
+    );
+    assert_eq!(
+        db.compare_and_swap(category.clone(), &data1, Some(&data2), None),
+        Ok(true)
+    );
+    assert_eq!(get_value(db, &data1, category.clone()), Ok(None));
+
+    // Only one of the racing writers wins.
+    let inserted = std::thread::scope(|s| {
+        let handles: Vec<_> = (0..8u8)
+            .map(|i| {
+                let category = category.clone();
+                let key = data2.clone();
+                s.spawn(move || db.insert_if_absent(category, key, vec![i]).unwrap())
+            })
+            .collect();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().unwrap())
+            .filter(|inserted| *inserted)
+            .count()
+    });
+    assert_eq!(inserted, 1);
+    db.remove(category, &data2).unwrap();
+}
+
+// The config of `merge`.
+#[cfg(test)]
+pub fn merge_config() -> Config {
+    let mut config = Config::with_category_num(NUM_COLUMNS);
+    for (category, operator) in [
+        (DataCategory::State, MergeOperator::U64Add),
+        (DataCategory::Headers, MergeOperator::Append),
+    ] {
+        config.categories.insert(
+            category,
+            CategoryConfig {
+                merge_operator: Some(operator),
+                ..Default::default()
+            },
+        );
+    }
+    config
+}
+
+// Merge with the operators of `merge_config`.
+#[cfg(test)]
+pub fn merge<D: Database>(db: &D) {
+    let counter = Some(DataCategory::State);
+    let log = Some(DataCategory::Headers);
+    let data = b"test".to_vec();
+
+    for _ in 0..3 {
+        db.merge(counter.clone(), data.clone(), 2u64.to_le_bytes().to_vec())
+            .unwrap();
+    }
+    assert_eq!(
+        get_value(db, &data, counter.clone()),
+        Ok(Some(6u64.to_le_bytes().to_vec()))
+    );
+    assert_eq!(
+        db.merge(counter.clone(), data.clone(), b"1".to_vec()),
+        Err(DatabaseError::InvalidData)
+    );
+
+    db.insert(log.clone(), data.clone(), b"a".to_vec()).unwrap();
+    db.merge(log.clone(), data.clone(), b"b".to_vec()).unwrap();
+    db.merge(log.clone(), data.clone(), b"c".to_vec()).unwrap();
+    assert_eq!(get_value(db, &data, log.clone()), Ok(Some(b"abc".to_vec())));
+
+    // Merge in a batch, after a write of the same batch.
+    let mut batch = Batch::new();
+    batch.insert(log.clone(), data.clone(), b"x".to_vec());
+    batch.merge(log.clone(), data.clone(), b"y".to_vec());
+    batch.merge(counter.clone(), data.clone(), 1u64.to_le_bytes().to_vec());
+    db.write(batch).unwrap();
+    assert_eq!(get_value(db, &data, log.clone()), Ok(Some(b"xy".to_vec())));
+    assert_eq!(
+        get_value(db, &data, counter.clone()),
+        Ok(Some(7u64.to_le_bytes().to_vec()))
+    );
+
+    // A batch with an invalid operand is not written.
+    let mut batch = Batch::new();
+    batch.insert(log.clone(), data.clone(), b"z".to_vec());
+    batch.merge(counter.clone(), data.clone(), b"1".to_vec());
+    assert_eq!(db.write(batch), Err(DatabaseError::InvalidData));
+    assert_eq!(get_value(db, &data, log), Ok(Some(b"xy".to_vec())));
+
+    // No merge operator
+    assert_eq!(
+        db.merge(Some(DataCategory::Extra), data.clone(), data.clone()),
+        Err(DatabaseError::InvalidData)
+    );
+    assert_eq!(
+        db.merge(None, data.clone(), data),
+        Err(DatabaseError::InvalidData)
+    );
+}
+
+// Merge into a malformed value with the operators of `merge_config`, which
+// fails but in RocksDB.
+#[cfg(test)]
+pub fn merge_malformed<D: Database>(db: &D) {
+    let counter = Some(DataCategory::State);
+    let data = b"malformed".to_vec();
+
+    db.insert(counter.clone(), data.clone(), b"1".to_vec())
+        .unwrap();
+    assert_eq!(
+        db.merge(counter.clone(), data.clone(), 2u64.to_le_bytes().to_vec()),
+        Err(DatabaseError::InvalidData)
+    );
+    let mut batch = Batch::new();
+    batch.merge(counter.clone(), data.clone(), 2u64.to_le_bytes().to_vec());
+    assert_eq!(db.write(batch), Err(DatabaseError::InvalidData));
+    assert_eq!(
+        get_value(db, &data, counter.clone()),
+        Ok(Some(b"1".to_vec()))
+    );
+    db.remove(counter, &data).unwrap();
+}
@@ -0,0 +1,393 @@
+use crate::database::{DataCategory, Result};
+#[cfg(feature = "rocksdb")]
+use crate::{
+    batch::{Batch, BatchOp},
+    columns::map_columns,
+    config::Config,
+    database::{DBIterator, Database},
+    error::DatabaseError,
+    refcount,
+    rocksdb::{column_descriptors, open_options, read_entries, read_value, stored_value},
+};
+#[cfg(feature = "rocksdb")]
+use rocksdb::{
+    ColumnFamily, ErrorKind, IteratorMode, OptimisticTransactionDB, OptimisticTransactionOptions,
+    WriteBatchWithTransaction, WriteOptions,
+};
+
+/// A transaction across data categories with optimistic concurrency control.
+///
+/// The keys read by `get_for_update` are checked at `commit`, which fails with
+/// `DatabaseError::Conflict` if another writer changed any of them since. The
+/// writes are invisible to others until the commit, and dropping the transaction
+/// discards them.
+pub trait Transaction {
+    /// Read the key and track it for conflicts.
+    fn get_for_update(
+        &mut self,
+        category: Option<DataCategory>,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>>;
+
+    fn put(&mut self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()>;
+
+    fn delete(&mut self, category: Option<DataCategory>, key: &[u8]) -> Result<()>;
+
+    /// Apply the writes atomically, if no key read has been changed by another writer.
+    fn commit(self) -> Result<()>;
+}
+
+/// A RocksDB opened for optimistic transactions.
+///
+/// It is also a `Database` with the semantics of `RocksDB`, and its writes are
+/// checked by the transactions for conflicts.
+#[cfg(feature = "rocksdb")]
+pub struct OptimisticDB {
+    db: OptimisticTransactionDB,
+    config: Config,
+    write_opts: WriteOptions,
+}
+
+/// A transaction of `OptimisticDB`.
+#[cfg(feature = "rocksdb")]
+pub struct OptimisticTransaction<'a> {
+    db: &'a OptimisticDB,
+    txn: rocksdb::Transaction<'a, OptimisticTransactionDB>,
+}
+
+#[cfg(feature = "rocksdb")]
+impl OptimisticDB {
+    /// Open the database with config, the same as `RocksDB::open`.
+    pub fn open(path: &str, config: &Config) -> Result<Self> {
+        let (opts, write_opts) = open_options(config)?;
+        let db = match config.category_num {
+            Some(_) => OptimisticTransactionDB::open_cf_descriptors(
+                &opts,
+                path,
+                column_descriptors(&opts, config),
+            )?,
+            None => OptimisticTransactionDB::open(&opts, path)?,
+        };
+
+        Ok(OptimisticDB {
+            db,
+            config: config.clone(),
+            write_opts,
+        })
+    }
+
+    /// Begin a transaction.
+    pub fn transaction(&self) -> OptimisticTransaction<'_> {
+        let txn = self
+            .db
+            .transaction_opt(&self.write_opts, &OptimisticTransactionOptions::default());
+        OptimisticTransaction { db: self, txn }
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.db.flush()?;
+        Ok(())
+    }
+
+    fn get_column(&self, category: &DataCategory) -> Result<&ColumnFamily> {
+        self.db
+            .cf_handle(map_columns(category.clone()))
+            .ok_or(DatabaseError::NotFound)
+    }
+
+    fn is_ref_counted(&self, category: &DataCategory) -> bool {
+        self.config.category(category).ref_counted
+    }
+
+    // Add the operation to the batch, as `RocksDB::write` does.
+    fn batch_op(&self, batch: &mut WriteBatchWithTransaction<true>, op: BatchOp) -> Result<()> {
+        match op {
+            BatchOp::Insert {
+                category: Some(category),
+                key,
+                value,
+            } => {
+                let col = self.get_column(&category)?;
+                if self.is_ref_counted(&category) {
+                    batch.merge_cf(col, key, refcount::encode_insert(&value));
+                } else {
+                    batch.put_cf(col, key, stored_value(&self.config, &category, &value));
+                }
+            }
+            BatchOp::Insert {
+                category: None,
+                key,
+                value,
+            } => batch.put(key, value),
+            BatchOp::Remove {
+                category: Some(category),
+                key,
+            } => {
+                let col = self.get_column(&category)?;
+                if self.is_ref_counted(&category) {
+                    batch.merge_cf(col, key, refcount::encode_remove());
+                } else {
+                    batch.delete_cf(col, key);
+                }
+            }
+            BatchOp::Remove {
+                category: None,
+                key,
+            } => batch.delete(key),
+            BatchOp::Merge {
+                category,
+                key,
+                operand,
+            } => {
+                let category = category.ok_or(DatabaseError::InvalidData)?;
+                self.config
+                    .category(&category)
+                    .merge_operator()
+                    .ok_or(DatabaseError::InvalidData)?
+                    .check_operand(&operand)?;
+                batch.merge_cf(self.get_column(&category)?, key, operand);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "rocksdb")]
+impl Database for OptimisticDB {
+    fn get(&self, category: Option<DataCategory>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match category {
+            Some(category) => {
+                let value = self.db.get_cf(self.get_column(&category)?, key)?;
+                read_value(&self.config, &category, value)
+            }
+            None => Ok(self.db.get(key)?),
+        }
+    }
+
+    fn get_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: &[Vec<u8>],
+    ) -> Result<Vec<Option<Vec<u8>>>> {
+        keys.iter()
+            .map(|key| self.get(category.clone(), key))
+            .collect()
+    }
+
+    fn insert(&self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.insert(category, key, value);
+        self.write(batch)
+    }
+
+    fn insert_batch(
+        &self,
+        category: Option<DataCategory>,
+        keys: Vec<Vec<u8>>,
+        values: Vec<Vec<u8>>,
+    ) -> Result<()> {
+        if keys.len() != values.len() {
+            return Err(DatabaseError::InvalidData);
+        }
+
+        let mut batch = Batch::new();
+        for (key, value) in keys.into_iter().zip(values) {
+            batch.insert(category.clone(), key, value);
+        }
+        self.write(batch)
+    }
+
+    fn contains(&self, category: Option<DataCategory>, key: &[u8]) -> Result<bool> {
+        Ok(self.get(category, key)?.is_some())
+    }
+
+    fn remove(&self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.remove(category, key.to_vec());
+        self.write(batch)
+    }
+
+    fn remove_batch(&self, category: Option<DataCategory>, keys: &[Vec<u8>]) -> Result<()> {
+        let mut batch = Batch::new();
+        for key in keys {
+            batch.remove(category.clone(), key.to_vec());
+        }
+        self.write(batch)
+    }
+
+    fn merge(&self, category: Option<DataCategory>, key: Vec<u8>, operand: Vec<u8>) -> Result<()> {
+        let mut batch = Batch::new();
+        batch.merge(category, key, operand);
+        self.write(batch)
+    }
+
+    /// Atomic with the other writes, in a transaction which is retried on a
+    /// conflict. Return `DatabaseError::InvalidData` for a ref-counted category.
+    fn compare_and_swap(
+        &self,
+        category: Option<DataCategory>,
+        key: &[u8],
+        expected: Option<&[u8]>,
+        new: Option<Vec<u8>>,
+    ) -> Result<bool> {
+        if category
+            .as_ref()
+            .is_some_and(|category| self.is_ref_counted(category))
+        {
+            return Err(DatabaseError::InvalidData);
+        }
+        loop {
+            let mut txn = self.transaction();
+            if txn.get_for_update(category.clone(), key)?.as_deref() != expected {
+                return Ok(false);
+            }
+            match new.clone() {
+                Some(value) => txn.put(category.clone(), key.to_vec(), value)?,
+                None => txn.delete(category.clone(), key)?,
+            }
+            match txn.commit() {
+                Err(DatabaseError::Conflict) => continue,
+                result => return result.map(|_| true),
+            }
+        }
+    }
+
+    fn write(&self, batch: Batch) -> Result<()> {
+        let mut write_batch = WriteBatchWithTransaction::<true>::default();
+        for op in batch {
+            self.batch_op(&mut write_batch, op)?;
+        }
+        self.db.write_opt(write_batch, &self.write_opts)?;
+        Ok(())
+    }
+
+    /// Not supported, restore it with `RocksDB` before opening.
+    fn restore(&mut self, _new_db: &str) -> Result<()> {
+        Err(DatabaseError::Internal(
+            "restore is not supported by the optimistic transaction database".to_string(),
+        ))
+    }
+
+    fn iterator(&self, category: Option<DataCategory>) -> Option<DBIterator<'_>> {
+        match category {
+            Some(category) => {
+                let col = self.get_column(&category).ok()?;
+                let iter = self.db.iterator_cf(col, IteratorMode::Start);
+                Some(read_entries(&self.config, category, iter))
+            }
+            None => Some(Box::new(
+                self.db
+                    .iterator(IteratorMode::Start)
+                    .map(|item| item.map_err(DatabaseError::from)),
+            )),
+        }
+    }
+
+    /// The database is closed when it is dropped.
+    fn close(&mut self) {}
+
+    fn flush(&self) -> Result<()> {
+        OptimisticDB::flush(self)
+    }
+}
+
+// RocksDB guarantees synchronization
+#[cfg(feature = "rocksdb")]
+unsafe impl Sync for OptimisticDB {}
+#[cfg(feature = "rocksdb")]
+unsafe impl Send for OptimisticDB {}
+
+#[cfg(feature = "rocksdb")]
+impl<'a> Transaction for OptimisticTransaction<'a> {
+    fn get_for_update(
+        &mut self,
+        category: Option<DataCategory>,
+        key: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        match category {
+            Some(category) => {
+                let col = self.db.get_column(&category)?;
+                let value = self.txn.get_for_update_cf(col, key, true)?;
+                read_value(&self.db.config, &category, value)
+            }
+            None => Ok(self.txn.get_for_update(key, true)?),
+        }
+    }
+
+    fn put(&mut self, category: Option<DataCategory>, key: Vec<u8>, value: Vec<u8>) -> Result<()> {
+        match category {
+            Some(category) => {
+                let col = self.db.get_column(&category)?;
+                if self.db.is_ref_counted(&category) {
+                    self.txn
+                        .merge_cf(col, key, refcount::encode_insert(&value))?;
+                } else {
+                    self.txn
+                        .put_cf(col, key, stored_value(&self.db.config, &category, &value))?;
+                }
+            }
+            None => self.txn.put(key, value)?,
+        }
+        Ok(())
+    }
+
+    fn delete(&mut self, category: Option<DataCategory>, key: &[u8]) -> Result<()> {
+        match category {
+            Some(category) => {
+                let col = self.db.get_column(&category)?;
+                if self.db.is_ref_counted(&category) {
+                    self.txn.merge_cf(col, key, refcount::encode_remove())?;
+                } else {
+                    self.txn.delete_cf(col, key)?;
+                }
+            }
+            None => self.txn.delete(key)?,
+        }
+        Ok(())
+    }
+
+    fn commit(self) -> Result<()> {
+        self.txn.commit().map_err(|e| match e.kind() {
+            ErrorKind::Busy | ErrorKind::TryAgain => DatabaseError::Conflict,
+            _ => e.into(),
+        })
+    }
+}
+
+#[cfg(all(test, feature = "rocksdb"))]
+mod tests {
+    use super::OptimisticDB;
+    use crate::columns::NUM_COLUMNS;
+    use crate::config::Config;
+    use crate::database::DataCategory;
+    use crate::test::{
+        batch_op, compare_and_swap, insert_get_contains_remove, merge, merge_config,
+        transaction_conflict, write_batch,
+    };
+    use std::fs::remove_dir_all;
+
+    #[test]
+    fn test_database() {
+        let path = "rocksdb_test/optimistic_database";
+        {
+            let db = OptimisticDB::open(path, &merge_config()).unwrap();
+            insert_get_contains_remove(&db, None);
+            insert_get_contains_remove(&db, Some(DataCategory::Extra));
+            batch_op(&db, Some(DataCategory::Extra));
+            write_batch(&db);
+            compare_and_swap(&db, Some(DataCategory::Extra));
+            merge(&db);
+        }
+        remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_transaction_conflict() {
+        let path = "rocksdb_test/optimistic_transaction_conflict";
+        {
+            let db = OptimisticDB::open(path, &Config::with_category_num(NUM_COLUMNS)).unwrap();
+            transaction_conflict(|| db.transaction());
+        }
+        remove_dir_all(path).unwrap();
+    }
+}
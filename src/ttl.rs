@@ -0,0 +1,176 @@
+//! Time-to-live of the values of a category.
+//!
+//! The value is stored with the time it is written as a suffix, `[value][written
+//! at: u64 LE]`. Expired values are not returned by reads, and are removed by the
+//! compaction filter. Only `RocksDB` applies the time-to-live.
+
+use std::fmt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "rocksdb")]
+use crate::database::Result;
+#[cfg(feature = "rocksdb")]
+use crate::error::DatabaseError;
+#[cfg(feature = "rocksdb")]
+use rocksdb::CompactionDecision;
+
+#[cfg(feature = "rocksdb")]
+pub(crate) const COMPACTION_FILTER_NAME: &str = "ttl_filter";
+
+#[cfg(feature = "rocksdb")]
+const SUFFIX_LEN: usize = 8;
+
+/// The clock of the time-to-live, in seconds or in block heights.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> u64;
+}
+
+/// The system clock, in seconds since the Unix epoch.
+#[derive(Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+    }
+}
+
+/// A clock of the block height, set by the chain after each block.
+#[derive(Debug, Default)]
+pub struct HeightClock {
+    height: AtomicU64,
+}
+
+impl HeightClock {
+    pub fn new(height: u64) -> Self {
+        HeightClock {
+            height: AtomicU64::new(height),
+        }
+    }
+
+    pub fn set(&self, height: u64) {
+        self.height.store(height, Ordering::SeqCst);
+    }
+}
+
+impl Clock for HeightClock {
+    fn now(&self) -> u64 {
+        self.height.load(Ordering::SeqCst)
+    }
+}
+
+/// The time-to-live of a category, see `CategoryConfig::ttl`.
+#[derive(Clone)]
+pub struct Ttl {
+    /// The values expire after this long in the unit of the clock.
+    pub duration: u64,
+    pub clock: Arc<dyn Clock>,
+}
+
+impl Ttl {
+    /// Expire the values after the number of seconds.
+    pub fn seconds(duration: u64) -> Self {
+        Ttl {
+            duration,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Keep the values of the last number of blocks, by the height of the clock.
+    pub fn blocks(duration: u64, clock: Arc<HeightClock>) -> Self {
+        Ttl { duration, clock }
+    }
+
+    /// Add the time it is written to the value.
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn encode(&self, value: &[u8]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(value.len() + SUFFIX_LEN);
+        bytes.extend_from_slice(value);
+        bytes.extend_from_slice(&self.clock.now().to_le_bytes());
+        bytes
+    }
+
+    /// Get the value stored, `None` if it is expired.
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn decode(&self, mut stored: Vec<u8>) -> Result<Option<Vec<u8>>> {
+        if self.is_expired(&stored)? {
+            return Ok(None);
+        }
+        stored.truncate(stored.len() - SUFFIX_LEN);
+        Ok(Some(stored))
+    }
+
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn is_expired(&self, stored: &[u8]) -> Result<bool> {
+        let (_, suffix) = split(stored)?;
+        let mut written_at = [0u8; SUFFIX_LEN];
+        written_at.copy_from_slice(suffix);
+        let expire_at = u64::from_le_bytes(written_at).saturating_add(self.duration);
+        Ok(self.clock.now() >= expire_at)
+    }
+
+    /// Remove the expired values.
+    #[cfg(feature = "rocksdb")]
+    pub(crate) fn compaction_filter(
+        &self,
+    ) -> impl FnMut(u32, &[u8], &[u8]) -> CompactionDecision + Send + 'static {
+        let ttl = self.clone();
+        move |_level, _key, value| match ttl.is_expired(value) {
+            Ok(true) => CompactionDecision::Remove,
+            _ => CompactionDecision::Keep,
+        }
+    }
+}
+
+/// Split what is stored into the value and the time it is written.
+#[cfg(feature = "rocksdb")]
+pub(crate) fn split(stored: &[u8]) -> Result<(&[u8], &[u8])> {
+    if stored.len() < SUFFIX_LEN {
+        return Err(DatabaseError::InvalidData);
+    }
+    Ok(stored.split_at(stored.len() - SUFFIX_LEN))
+}
+
+impl fmt::Debug for Ttl {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Ttl")
+            .field("duration", &self.duration)
+            .field("now", &self.clock.now())
+            .finish()
+    }
+}
+
+#[cfg(all(test, feature = "rocksdb"))]
+mod tests {
+    use super::{HeightClock, Ttl};
+    use rocksdb::CompactionDecision;
+    use std::sync::Arc;
+
+    #[test]
+    fn test_expire() {
+        let clock = Arc::new(HeightClock::new(10));
+        let ttl = Ttl::blocks(2, Arc::clone(&clock));
+        let stored = ttl.encode(b"test");
+
+        clock.set(11);
+        assert_eq!(ttl.decode(stored.clone()), Ok(Some(b"test".to_vec())));
+        let mut filter = ttl.compaction_filter();
+        match filter(0, b"key", &stored) {
+            CompactionDecision::Keep => (), // pass
+            _ => panic!("should keep the value not expired"),
+        }
+
+        clock.set(12);
+        assert_eq!(ttl.decode(stored.clone()), Ok(None));
+        match filter(0, b"key", &stored) {
+            CompactionDecision::Remove => (), // pass
+            _ => panic!("should remove the expired value"),
+        }
+        assert!(ttl.decode(b"test".to_vec()).is_err());
+    }
+}
@@ -0,0 +1,293 @@
+use std::collections::HashSet;
+
+use crate::batch::{Batch, BatchOp};
+use crate::database::{DataCategory, Database, Result};
+use crate::error::DatabaseError;
+use crate::export::{category_to_tag, tag_to_category};
+
+// The key of the latest height in the undo log.
+const LATEST_KEY: &[u8] = b"undo-latest-height";
+// The key of the earliest height in the undo log.
+const EARLIEST_KEY: &[u8] = b"undo-earliest-height";
+// The prefix of the undo record keys, followed by the height.
+const RECORD_PREFIX: &[u8] = b"undo-";
+
+/// An undo log of the blocks, to roll back the chain to an earlier height.
+///
+/// For every block batch, the previous values of the keys it writes are
+/// recorded in `DataCategory::Undo`. The ref-counted categories can not be
+/// rolled back, since their values are restored without the references.
+pub struct UndoLog {
+    depth: Option<u64>,
+}
+
+// A key written by a block and its previous value, `None` if it did not exist.
+type UndoEntry = (Option<DataCategory>, Vec<u8>, Option<Vec<u8>>);
+
+// The undo entries of a block.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct UndoRecord {
+    entries: Vec<UndoEntry>,
+}
+
+impl UndoLog {
+    /// Create it with the number of recent blocks that can be rolled back,
+    /// `None` to keep the undo records of all blocks.
+    pub fn new(depth: Option<u64>) -> Self {
+        UndoLog { depth }
+    }
+
+    /// Record the previous values of the keys written by the batch of the block
+    /// at the height.
+    ///
+    /// The undo record is added to the batch, so call it after all the writes of
+    /// the block are in the batch. The blocks must be recorded in order of height.
+    pub fn record<D: Database>(&self, db: &D, batch: &mut Batch, height: u64) -> Result<()> {
+        let mut seen = HashSet::new();
+        let mut record = UndoRecord::default();
+        for op in batch.ops() {
+            let (category, key) = match op {
+                BatchOp::Insert { category, key, .. }
+                | BatchOp::Remove { category, key }
+                | BatchOp::Merge { category, key, .. } => (category, key),
+            };
+            if *category == Some(DataCategory::Undo) || !seen.insert((category, key)) {
+                continue;
+            }
+            let previous = db.get(category.clone(), key)?;
+            record
+                .entries
+                .push((category.clone(), key.to_vec(), previous));
+        }
+
+        let earliest = match read_height(db, EARLIEST_KEY)? {
+            Some(earliest) => earliest,
+            None => height,
+        };
+        let mut new_earliest = earliest;
+        if let Some(depth) = self.depth {
+            new_earliest = (height + 1).saturating_sub(depth).max(earliest);
+            for expired in earliest..new_earliest {
+                batch.remove(Some(DataCategory::Undo), record_key(expired));
+            }
+        }
+
+        if new_earliest <= height {
+            batch.insert(
+                Some(DataCategory::Undo),
+                record_key(height),
+                record.encode(),
+            );
+        }
+        batch.insert(
+            Some(DataCategory::Undo),
+            EARLIEST_KEY.to_vec(),
+            new_earliest.to_be_bytes().to_vec(),
+        );
+        batch.insert(
+            Some(DataCategory::Undo),
+            LATEST_KEY.to_vec(),
+            height.to_be_bytes().to_vec(),
+        );
+
+        Ok(())
+    }
+
+    /// Roll back the blocks after the height atomically.
+    ///
+    /// Return `DatabaseError::NotFound` if the undo record of any of them is missing.
+    pub fn rollback_to<D: Database>(&self, db: &D, height: u64) -> Result<()> {
+        let latest = match read_height(db, LATEST_KEY)? {
+            Some(latest) if latest > height => latest,
+            _ => return Ok(()),
+        };
+
+        let mut batch = Batch::new();
+        // The newest blocks first, so the values before the height win.
+        for h in (height + 1..=latest).rev() {
+            let record = db
+                .get(Some(DataCategory::Undo), &record_key(h))?
+                .ok_or(DatabaseError::NotFound)
+                .and_then(|bytes| UndoRecord::decode(&bytes))?;
+            for (category, key, previous) in record.entries {
+                match previous {
+                    Some(value) => batch.insert(category, key, value),
+                    None => batch.remove(category, key),
+                }
+            }
+            batch.remove(Some(DataCategory::Undo), record_key(h));
+        }
+
+        let earliest = read_height(db, EARLIEST_KEY)?.unwrap_or(height);
+        if height < earliest {
+            batch.remove(Some(DataCategory::Undo), EARLIEST_KEY.to_vec());
+            batch.remove(Some(DataCategory::Undo), LATEST_KEY.to_vec());
+        } else {
+            batch.insert(
+                Some(DataCategory::Undo),
+                LATEST_KEY.to_vec(),
+                height.to_be_bytes().to_vec(),
+            );
+        }
+
+        db.write(batch)
+    }
+}
+
+impl UndoRecord {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for (category, key, previous) in self.entries.iter() {
+            bytes.push(category_to_tag(category));
+            encode_bytes(&mut bytes, key);
+            match previous {
+                Some(value) => {
+                    bytes.push(1);
+                    encode_bytes(&mut bytes, value);
+                }
+                None => bytes.push(0),
+            }
+        }
+        bytes
+    }
+
+    fn decode(mut bytes: &[u8]) -> Result<Self> {
+        let mut entries = Vec::new();
+        while !bytes.is_empty() {
+            let category = tag_to_category(decode_u8(&mut bytes)?)?;
+            let key = decode_bytes(&mut bytes)?;
+            let previous = match decode_u8(&mut bytes)? {
+                0 => None,
+                1 => Some(decode_bytes(&mut bytes)?),
+                _ => return Err(DatabaseError::InvalidData),
+            };
+            entries.push((category, key, previous));
+        }
+
+        Ok(UndoRecord { entries })
+    }
+}
+
+fn encode_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    bytes.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    bytes.extend_from_slice(data);
+}
+
+fn decode_u8(bytes: &mut &[u8]) -> Result<u8> {
+    let (&byte, rest) = bytes.split_first().ok_or(DatabaseError::InvalidData)?;
+    *bytes = rest;
+    Ok(byte)
+}
+
+fn decode_bytes(bytes: &mut &[u8]) -> Result<Vec<u8>> {
+    if bytes.len() < 4 {
+        return Err(DatabaseError::InvalidData);
+    }
+    let (len, rest) = bytes.split_at(4);
+    let len = u32::from_be_bytes([len[0], len[1], len[2], len[3]]) as usize;
+    if rest.len() < len {
+        return Err(DatabaseError::InvalidData);
+    }
+    let (data, rest) = rest.split_at(len);
+    *bytes = rest;
+    Ok(data.to_vec())
+}
+
+fn read_height<D: Database>(db: &D, key: &[u8]) -> Result<Option<u64>> {
+    match db.get(Some(DataCategory::Undo), key)? {
+        Some(bytes) => {
+            let mut height = [0u8; 8];
+            if bytes.len() != height.len() {
+                return Err(DatabaseError::InvalidData);
+            }
+            height.copy_from_slice(&bytes);
+            Ok(Some(u64::from_be_bytes(height)))
+        }
+        None => Ok(None),
+    }
+}
+
+fn record_key(height: u64) -> Vec<u8> {
+    [RECORD_PREFIX, &height.to_be_bytes()].concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UndoLog, UndoRecord};
+    use crate::batch::Batch;
+    use crate::database::{DataCategory, Database};
+    use crate::error::DatabaseError;
+    use crate::memorydb::MemoryDB;
+
+    // Commit a block that sets the header and the state.
+    fn commit(db: &MemoryDB, undo: &UndoLog, height: u64, state: Option<&[u8]>) {
+        let mut batch = Batch::new();
+        batch.insert(
+            Some(DataCategory::Headers),
+            height.to_be_bytes().to_vec(),
+            b"header".to_vec(),
+        );
+        match state {
+            Some(value) => batch.insert(Some(DataCategory::State), b"key".to_vec(), value.to_vec()),
+            None => batch.remove(Some(DataCategory::State), b"key".to_vec()),
+        }
+        undo.record(db, &mut batch, height).unwrap();
+        db.write(batch).unwrap();
+    }
+
+    fn state(db: &MemoryDB) -> Option<Vec<u8>> {
+        db.get(Some(DataCategory::State), b"key").unwrap()
+    }
+
+    #[test]
+    fn test_rollback() {
+        let db = MemoryDB::open();
+        let undo = UndoLog::new(None);
+
+        commit(&db, &undo, 1, Some(b"a"));
+        commit(&db, &undo, 2, Some(b"b"));
+        commit(&db, &undo, 3, None);
+        commit(&db, &undo, 4, Some(b"d"));
+
+        undo.rollback_to(&db, 2).unwrap();
+        assert_eq!(state(&db), Some(b"b".to_vec()));
+        let headers = Some(DataCategory::Headers);
+        assert_eq!(db.contains(headers.clone(), &2u64.to_be_bytes()), Ok(true));
+        assert_eq!(db.contains(headers.clone(), &3u64.to_be_bytes()), Ok(false));
+
+        // Continue from the height rolled back to.
+        commit(&db, &undo, 3, Some(b"c"));
+        undo.rollback_to(&db, 0).unwrap();
+        assert_eq!(state(&db), None);
+        assert_eq!(db.contains(headers, &1u64.to_be_bytes()), Ok(false));
+    }
+
+    #[test]
+    fn test_depth() {
+        let db = MemoryDB::open();
+        let undo = UndoLog::new(Some(2));
+
+        for height in 1..=4 {
+            commit(&db, &undo, height, Some(&height.to_be_bytes()));
+        }
+
+        // The records of the blocks 1 and 2 are removed.
+        assert_eq!(undo.rollback_to(&db, 1), Err(DatabaseError::NotFound));
+        assert_eq!(state(&db), Some(4u64.to_be_bytes().to_vec()));
+        undo.rollback_to(&db, 2).unwrap();
+        assert_eq!(state(&db), Some(2u64.to_be_bytes().to_vec()));
+    }
+
+    #[test]
+    fn test_undo_record() {
+        let record = UndoRecord {
+            entries: vec![
+                (None, b"a".to_vec(), Some(vec![])),
+                (Some(DataCategory::State), b"b".to_vec(), None),
+            ],
+        };
+
+        assert_eq!(UndoRecord::decode(&record.encode()), Ok(record));
+        assert!(UndoRecord::decode(b"test").is_err());
+    }
+}